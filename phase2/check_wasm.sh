@@ -0,0 +1,58 @@
+#!/usr/bin/env bash
+# 🌐 WASM compile check for codex_cli_optimization_v1.rs
+#
+# Wraps the core module in a throwaway crate (same dependencies as step 1 of
+# integration_patch.rs) and compiles it for wasm32-unknown-unknown: without
+# the clock/filesystem defaults, with chrono, and with tokio. Tests are built
+# with --no-run; nothing executes on the target. Fails on any error or clippy
+# warning.
+#
+# Needs: rustup target add wasm32-unknown-unknown
+set -euo pipefail
+
+core="$(cd "$(dirname "$0")" && pwd)/codex_cli_optimization_v1.rs"
+crate_dir="$(mktemp -d)"
+trap 'rm -rf "$crate_dir"' EXIT
+
+mkdir -p "$crate_dir/src"
+cat > "$crate_dir/Cargo.toml" <<'EOF'
+[package]
+name = "wasm-check"
+version = "0.0.0"
+edition = "2021"
+publish = false
+
+[dependencies]
+chrono = { version = "0.4", features = ["serde"], optional = true }
+serde = { version = "1.0", features = ["derive"] }
+serde_json = "1.0"
+log = "0.4"
+regex = "1"
+tiktoken-rs = { version = "0.6", optional = true }
+rmp-serde = { version = "1.3", optional = true }
+tokio = { version = "1", features = ["sync"], optional = true }
+tokio-stream = { version = "0.1", features = ["sync"], optional = true }
+
+[target.'cfg(all(target_arch = "wasm32", target_os = "unknown"))'.dependencies]
+chrono = { version = "0.4", features = ["serde", "wasmbind"], optional = true }
+
+[features]
+default = ["chrono", "fs"]
+chrono = ["dep:chrono"]
+fs = []
+tiktoken = ["dep:tiktoken-rs"]
+binary = ["fs", "dep:rmp-serde"]
+tokio = ["dep:tokio", "dep:tokio-stream"]
+EOF
+cat > "$crate_dir/src/lib.rs" <<EOF
+#[path = "$core"]
+pub mod codex_cli_optimization_v1;
+EOF
+
+cd "$crate_dir"
+for features in "" "chrono" "chrono,tokio"; do
+    echo "== wasm32-unknown-unknown, features: [${features}]"
+    cargo clippy --target wasm32-unknown-unknown --no-default-features --features "$features" \
+        --all-targets -- -D warnings
+    cargo test --target wasm32-unknown-unknown --no-default-features --features "$features" --no-run
+done
@@ -4,9 +4,742 @@
 // Based on Phase 1 analysis: 99.2% of tokens come from unlimited Vec<ResponseItem> accumulation
 // This implementation provides intelligent history management with token-based limiting
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::future::Future;
+use std::io::{self, Write};
+#[cfg(feature = "fs")]
+use std::io::{BufReader, BufWriter};
+use std::ops::Range;
+#[cfg(feature = "fs")]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+/// Token counter used for all history bookkeeping
+///
+/// Implement this to plug in an exact tokenizer (e.g. tiktoken) instead of
+/// the character-based heuristics shipped with this module.
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+    /// Count the tokens `text` would consume
+    fn count(&self, text: &str) -> usize;
+}
+
+impl<T: Tokenizer + ?Sized> Tokenizer for Box<T> {
+    fn count(&self, text: &str) -> usize {
+        (**self).count(text)
+    }
+}
+
+/// Default tokenizer: ~3.5 bytes per token (the original Phase 2 estimate)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenizer;
+
+impl Tokenizer for HeuristicTokenizer {
+    fn count(&self, text: &str) -> usize {
+        // Rough estimate: ~4 characters per token for English
+        // More conservative estimate for mixed content
+        (text.len() as f64 / 3.5).ceil() as usize
+    }
+}
+
+/// Tunable tokenizer counting Unicode characters at a fixed ratio
+///
+/// Counts `chars()` rather than bytes, so a ratio of ~1.0 works for CJK text.
+#[derive(Debug, Clone, Copy)]
+pub struct CharRatioTokenizer {
+    pub chars_per_token: f64,
+}
+
+impl CharRatioTokenizer {
+    /// Create a tokenizer with the given characters-per-token ratio (must be > 0)
+    pub fn new(chars_per_token: f64) -> Self {
+        assert!(chars_per_token > 0.0, "chars_per_token must be positive");
+        Self { chars_per_token }
+    }
+}
+
+impl Tokenizer for CharRatioTokenizer {
+    fn count(&self, text: &str) -> usize {
+        (text.chars().count() as f64 / self.chars_per_token).ceil() as usize
+    }
+}
+
+/// Cheap estimate blending word and character counts, tuned on English prose
+///
+/// `per_word × (words + punctuation marks) + non-space chars / chars_per_token`:
+/// common words cost about one token each and the character term charges
+/// extra for long or rare ones, averaging ~1.33 tokens per English word.
+/// Undercounts unspaced scripts (CJK); use `CharRatioTokenizer` there.
+#[derive(Debug, Clone, Copy)]
+pub struct WordAwareTokenizer {
+    pub per_word: f64,
+    pub chars_per_token: f64,
+}
+
+impl Default for WordAwareTokenizer {
+    fn default() -> Self {
+        Self { per_word: 0.7, chars_per_token: 9.0 }
+    }
+}
+
+impl Tokenizer for WordAwareTokenizer {
+    fn count(&self, text: &str) -> usize {
+        let words = text.split_whitespace().count();
+        let punctuation = text.chars().filter(char::is_ascii_punctuation).count();
+        let chars = text.chars().filter(|c| !c.is_whitespace()).count();
+        (self.per_word * (words + punctuation) as f64 + chars as f64 / self.chars_per_token).ceil() as usize
+    }
+}
+
+/// Exact OpenAI token counts backed by `tiktoken-rs`
+#[cfg(feature = "tiktoken")]
+pub struct TiktokenTokenizer {
+    bpe: tiktoken_rs::CoreBPE,
+    encoding: &'static str,
+}
+
+#[cfg(feature = "tiktoken")]
+impl TiktokenTokenizer {
+    /// Create a tokenizer for `model` (e.g. "gpt-4o", "gpt-4", "gpt-3.5-turbo")
+    ///
+    /// Unknown models fall back to `cl100k_base` with a warning.
+    pub fn new(model: &str) -> Self {
+        let encoding = Self::encoding_for_model(model).unwrap_or_else(|| {
+            log::warn!("Unknown model '{}', falling back to cl100k_base", model);
+            "cl100k_base"
+        });
+        
+        let bpe = match encoding {
+            "o200k_base" => tiktoken_rs::o200k_base(),
+            _ => tiktoken_rs::cl100k_base(),
+        }
+        .expect("bundled tiktoken encoding should always load");
+        
+        Self { bpe, encoding }
+    }
+    
+    /// Name of the encoding in use
+    pub fn encoding(&self) -> &'static str {
+        self.encoding
+    }
+    
+    /// Map a model name to its encoding
+    fn encoding_for_model(model: &str) -> Option<&'static str> {
+        let model = model.to_lowercase();
+        
+        // Order matters: "gpt-4o" must be checked before "gpt-4"
+        if model.starts_with("gpt-4o") || model.starts_with("gpt-4.1")
+            || model.starts_with("o1") || model.starts_with("o3") || model.starts_with("o4")
+        {
+            Some("o200k_base")
+        } else if model.starts_with("gpt-4") || model.starts_with("gpt-3.5")
+            || model.starts_with("text-embedding-3") || model.starts_with("text-embedding-ada-002")
+        {
+            Some("cl100k_base")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl fmt::Debug for TiktokenTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TiktokenTokenizer")
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl Tokenizer for TiktokenTokenizer {
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+}
+
+/// Split markdown into prose and fenced code blocks (fences included)
+///
+/// An unclosed fence runs to the end of the content.
+fn split_code_fences(content: &str) -> (String, Vec<&str>) {
+    let mut prose = String::new();
+    let mut code_blocks = Vec::new();
+    let mut rest = content;
+    
+    while let Some(open) = rest.find("```") {
+        prose.push_str(&rest[..open]);
+        let after_open = &rest[open + 3..];
+        let close = after_open.find("```").map_or(rest.len(), |end| open + 3 + end + 3);
+        code_blocks.push(&rest[open..close]);
+        rest = &rest[close..];
+    }
+    prose.push_str(rest);
+    
+    (prose, code_blocks)
+}
+
+/// Append fenced code blocks to a prose summary, one per line, verbatim
+fn with_code_blocks(mut summary: String, code_blocks: &[&str]) -> String {
+    for block in code_blocks {
+        if !summary.is_empty() {
+            summary.push('\n');
+        }
+        summary.push_str(block);
+    }
+    summary
+}
+
+/// Share of `content`'s characters inside fenced code blocks (fences included)
+pub fn fenced_code_fraction(content: &str) -> f64 {
+    let total = content.chars().count();
+    if total == 0 {
+        return 0.0;
+    }
+    let (_, code_blocks) = split_code_fences(content);
+    let code: usize = code_blocks.iter().map(|block| block.chars().count()).sum();
+    code as f64 / total as f64
+}
+
+/// Whether `marker` occurs in `content` at the start of a word
+///
+/// Markers that start with a word character (e.g. `fn `) must not be preceded
+/// by one, so "fn " doesn't fire inside words; symbol markers like a code
+/// fence match anywhere.
+fn contains_code_marker(content: &str, marker: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    if !marker.starts_with(is_word) {
+        return content.contains(marker);
+    }
+    content
+        .match_indices(marker)
+        .any(|(at, _)| !content[..at].ends_with(is_word))
+}
+
+/// Spans a message quotes from earlier ones: `> ` lines and text between
+/// double quotes or backticks, at least `min_chars` characters long
+fn quoted_fragments(content: &str, min_chars: usize) -> Vec<&str> {
+    let mut fragments: Vec<&str> = content.lines()
+        .filter_map(|line| line.trim_start().strip_prefix("> "))
+        .map(str::trim)
+        .collect();
+    for delimiter in ['"', '`'] {
+        let parts: Vec<&str> = content.split(delimiter).collect();
+        // Odd parts are inside a pair; text after an unmatched delimiter is not
+        let paired = parts.len().saturating_sub(1);
+        fragments.extend(parts[..paired].iter().skip(1).step_by(2).map(|part| part.trim()));
+    }
+    fragments.retain(|fragment| fragment.chars().count() >= min_chars);
+    fragments
+}
+
+/// Lowercase `content` into `buf`, reusing its allocation
+///
+/// Folds per character, so a word-final Σ becomes σ rather than
+/// `str::to_lowercase`'s ς; keyword matching is unaffected in practice.
+fn lowercase_into(buf: &mut String, content: &str) {
+    buf.clear();
+    buf.extend(content.chars().flat_map(char::to_lowercase));
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn default_tokenizer() -> Arc<dyn Tokenizer> {
+    Arc::new(HeuristicTokenizer)
+}
+
+/// Serializes `Vec<Regex>` as its pattern strings
+mod regex_list {
+    use regex::Regex;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    
+    pub fn serialize<S: Serializer>(patterns: &[Regex], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(patterns.iter().map(Regex::as_str))
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|pattern| Regex::new(pattern).map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+/// Caller-supplied summarizer used in place of the built-in `create_summary`
+pub type Summarizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Message time: `chrono::DateTime<Utc>` by default, or epoch milliseconds
+/// when built without the `chrono` feature
+#[cfg(feature = "chrono")]
+pub type Timestamp = chrono::DateTime<chrono::Utc>;
+#[cfg(not(feature = "chrono"))]
+pub type Timestamp = u64;
+
+/// Difference between two `Timestamp`s
+#[cfg(feature = "chrono")]
+pub type TimeSpan = chrono::Duration;
+#[cfg(not(feature = "chrono"))]
+pub type TimeSpan = std::time::Duration;
+
+static TIME_SOURCE: OnceLock<Clock> = OnceLock::new();
+
+/// Install the process-wide time source behind `now_timestamp`; the first
+/// call wins and later ones return `false`
+///
+/// Needed on `wasm32-unknown-unknown`, where the system clock panics: pass
+/// e.g. `Date.now()` via `js_sys`. A history's own `with_clock` still takes
+/// precedence.
+pub fn set_time_source(source: impl Fn() -> Timestamp + Send + Sync + 'static) -> bool {
+    TIME_SOURCE.set(Arc::new(source)).is_ok()
+}
+
+/// Current wall-clock time as a `Timestamp`, from `set_time_source` if installed
+pub fn now_timestamp() -> Timestamp {
+    match TIME_SOURCE.get() {
+        Some(source) => source(),
+        None => system_now(),
+    }
+}
+
+#[cfg(feature = "chrono")]
+fn system_now() -> Timestamp {
+    chrono::Utc::now()
+}
+
+#[cfg(not(feature = "chrono"))]
+fn system_now() -> Timestamp {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Minutes from `earlier` to `later` (negative if `later` is actually earlier)
+#[cfg(feature = "chrono")]
+fn minutes_between(earlier: Timestamp, later: Timestamp) -> f64 {
+    later.signed_duration_since(earlier).num_milliseconds() as f64 / 60_000.0
+}
+
+#[cfg(not(feature = "chrono"))]
+fn minutes_between(earlier: Timestamp, later: Timestamp) -> f64 {
+    (later as f64 - earlier as f64) / 60_000.0
+}
+
+#[cfg(feature = "chrono")]
+fn span_between(earlier: Timestamp, later: Timestamp) -> TimeSpan {
+    later - earlier
+}
+
+#[cfg(not(feature = "chrono"))]
+fn span_between(earlier: Timestamp, later: Timestamp) -> TimeSpan {
+    std::time::Duration::from_millis(later.saturating_sub(earlier))
+}
+
+/// Time source used for recency scoring (defaults to `now_timestamp`)
+pub type Clock = Arc<dyn Fn() -> Timestamp + Send + Sync>;
+
+/// Caller-supplied veto on eviction: `true` means the message must be kept
+pub type RetentionFilter = Arc<dyn Fn(&ResponseItem) -> bool + Send + Sync>;
+
+/// Observable pruning activity delivered to `on_prune` subscribers
+#[derive(Debug, Clone, PartialEq)]
+pub enum PruneEvent {
+    /// A message was dropped from the history
+    Removed { message_type: MessageType, tokens: usize },
+    
+    /// The message at `index` was replaced by a summary
+    Compressed { index: usize, tokens_before: usize, tokens_after: usize },
+    
+    /// Utilization rose past one of `utilization_alert_thresholds`
+    ThresholdCrossed { pct: u32 },
+}
+
+type PruneSubscriber = Arc<Mutex<dyn FnMut(PruneEvent) + Send>>;
+
+/// Caller-supplied closures attached to a history (never serialized)
+#[derive(Clone, Default)]
+struct HistoryHooks {
+    summarizer: Option<Summarizer>,
+    clock: Option<Clock>,
+    retention_filter: Option<RetentionFilter>,
+    prune_subscribers: Vec<PruneSubscriber>,
+    #[cfg(feature = "tokio")]
+    stats_channel: std::sync::OnceLock<tokio::sync::broadcast::Sender<ConversationStats>>,
+}
+
+/// Snapshots a `stats_stream` subscriber can fall behind before skipping to the newest
+#[cfg(feature = "tokio")]
+const STATS_CHANNEL_CAPACITY: usize = 16;
+
+impl fmt::Debug for HistoryHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HistoryHooks")
+            .field("summarizer", &self.summarizer.is_some())
+            .field("clock", &self.clock.is_some())
+            .field("retention_filter", &self.retention_filter.is_some())
+            .field("prune_subscribers", &self.prune_subscribers.len())
+            .finish()
+    }
+}
+
+/// Keyword → importance delta, matched case-insensitively against content
+pub type KeywordWeights = HashMap<String, f64>;
+
+/// The built-in English keyword weights
+pub fn default_keyword_weights() -> KeywordWeights {
+    [
+        ("error", 0.2),
+        ("bug", 0.2),
+        ("important", 0.2),
+        ("critical", 0.2),
+        ("solution", 0.15),
+        ("fix", 0.15),
+    ]
+    .into_iter()
+    .map(|(keyword, weight)| (keyword.to_string(), weight))
+    .collect()
+}
+
+/// The built-in type → keyword overlaps: the words the wrapper's classifier
+/// assigns these types from, already paid for by the type weight
+pub fn default_type_implied_keywords() -> HashMap<MessageType, Vec<String>> {
+    [
+        (MessageType::ImportantDecision, vec!["important", "warning"]),
+        (MessageType::ErrorHandling, vec!["error"]),
+    ]
+    .into_iter()
+    .map(|(message_type, keywords)| (message_type, keywords.into_iter().map(String::from).collect()))
+    .collect()
+}
+
+/// The built-in substrings that make a message essential (never removed)
+pub fn default_essential_patterns() -> Vec<String> {
+    ["error:", "exception", "config", "setting"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+/// The built-in prefixes that make a message essential (never removed)
+pub fn default_essential_prefixes() -> Vec<String> {
+    vec!["system:".to_string()]
+}
+
+/// How `intelligent_prune` gets the history back under budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PruneStrategy {
+    /// Compress, then remove low-importance, then aggressive prune (default)
+    #[default]
+    Intelligent,
+    
+    /// Drop the oldest messages until the rest fit; no importance scoring
+    SlidingWindow,
+    
+    /// Only ever compress; never remove messages for token pressure
+    /// (`max_messages` still applies)
+    CompressOnly,
+    
+    /// Like `Intelligent`, but merges runs of older low-importance messages
+    /// into one summary each instead of compressing them one by one
+    Coalesce,
+}
+
+/// How the summed importance factors are mapped into [0, 1]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ScoreNormalization {
+    /// Hard clamp: scores beyond the range saturate (and tie) at 0.0 or 1.0
+    #[default]
+    Clamp,
+    
+    /// Logistic curve centred on 0.5: near-identity mid-range, and order is
+    /// preserved at the extremes
+    Sigmoid,
+}
+
+/// Steepness of `ScoreNormalization::Sigmoid`; 4.0 gives slope 1 at the centre
+const SIGMOID_STEEPNESS: f64 = 4.0;
+
+/// How `dedup` decides two messages are the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DedupComparison {
+    /// Byte-identical content
+    #[default]
+    Exact,
+    
+    /// Same words ignoring case and whitespace (compared by hash)
+    Normalized,
+}
+
+/// Which duplicates `dedup` removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupMode {
+    /// Only back-to-back duplicates (safe; typical of retries)
+    Consecutive,
+    
+    /// Any duplicate among the newest `window` messages (`usize::MAX` for all)
+    Global { window: usize },
+}
+
+/// What inserting a message that alone exceeds the token limit does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OversizedPolicy {
+    /// Refuse it: `try_add_message` errors, other inserts drop it with a warning
+    Reject,
+    
+    /// Cut it down to fit, like `max_single_message_tokens`
+    Truncate,
+    
+    /// Keep it and log a warning; the history stays over budget (default)
+    #[default]
+    AllowAndWarn,
+}
+
+/// Known models, for sizing a history with `for_model`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModelProfile {
+    Gpt4o,
+    Gpt4oMini,
+    Gpt4Turbo,
+    Gpt4,
+    Gpt35Turbo,
+    Claude3,
+}
+
+impl ModelProfile {
+    /// Total context window in tokens (prompt + completion)
+    pub fn context_window(self) -> usize {
+        match self {
+            Self::Gpt4o | Self::Gpt4oMini | Self::Gpt4Turbo => 128_000,
+            Self::Gpt4 => 8_192,
+            Self::Gpt35Turbo => 16_385,
+            Self::Claude3 => 200_000,
+        }
+    }
+    
+    /// Tokens left free for the model's reply
+    pub fn completion_reserve(self) -> usize {
+        match self {
+            Self::Gpt4o | Self::Gpt4oMini => 16_384,
+            Self::Gpt4 => 2_048,
+            Self::Gpt4Turbo | Self::Gpt35Turbo | Self::Claude3 => 4_096,
+        }
+    }
+    
+    /// History budget: the context window minus the completion reserve
+    pub fn max_tokens(self) -> usize {
+        self.context_window() - self.completion_reserve()
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum DedupKey<'a> {
+    Exact(&'a str),
+    Hash(u64),
+}
+
+/// `recency_half_life_minutes` sentinel restoring the old flat boost for messages under 60 minutes
+pub const LEGACY_RECENCY_CLIFF: f64 = 0.0;
+
+// 🔧 Optimization Configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OptimizationConfig {
+    pub max_tokens: usize,
+    pub min_messages: usize,
+    pub compression_threshold: f64,
+    pub enable_aggressive_pruning: bool,
+    
+    /// When false, pruning never rewrites content: messages are kept in full
+    /// or removed (`CompressOnly` then does nothing)
+    pub enable_compression: bool,
+    
+    /// Messages scoring below this are removed first under token pressure
+    pub removal_importance_threshold: f64,
+    
+    /// Aggressive pruning ranks by importance plus up to this much for recency,
+    /// scaled by position; 0.0 ranks by importance alone. The last
+    /// `min_messages` are kept regardless.
+    pub aggressive_recency_boost: f64,
+    
+    /// Messages at or under this many characters are never compressed
+    pub compression_min_length: usize,
+    
+    /// Keywords that raise (or lower) importance; each hit adds its weight
+    pub keyword_weights: KeywordWeights,
+    
+    /// Keywords not weighted again for messages of a type they imply, e.g.
+    /// "important" on an `ImportantDecision` (empty = always count keywords)
+    pub type_implied_keywords: HashMap<MessageType, Vec<String>>,
+    
+    /// Token caps per message type, enforced before the global limit
+    pub per_type_budgets: HashMap<MessageType, usize>,
+    
+    /// Utilization percentages that fire `PruneEvent::ThresholdCrossed`
+    pub utilization_alert_thresholds: Vec<u32>,
+    
+    /// Roles (e.g. "system", "developer") whose messages are never evicted
+    pub always_keep_roles: HashSet<String>,
+    
+    /// Never prune the last message of a `MessageType`: when a pass would
+    /// remove them all, its most important one stays
+    pub preserve_one_per_type: bool,
+    
+    /// Per-message framing tokens (role, `<|im_start|>` etc.) added to `current_tokens`
+    pub per_message_overhead: usize,
+    
+    /// Maximum importance boost for a brand-new message
+    pub recency_weight: f64,
+    
+    /// Minutes for the recency boost to halve (`LEGACY_RECENCY_CLIFF` for the flat 60-minute rule)
+    pub recency_half_life_minutes: f64,
+    
+    /// Pruning algorithm used by `intelligent_prune`
+    pub prune_strategy: PruneStrategy,
+    
+    /// Importance delta for each `MessageType::Custom` tag (unlisted tags: 0.0)
+    pub custom_type_weights: HashMap<String, f64>,
+    
+    /// Content comparison used by `dedup`
+    pub dedup_comparison: DedupComparison,
+    
+    /// Leading sentences kept by built-in summaries, up to this many tokens
+    pub summary_head_tokens: usize,
+    
+    /// Trailing sentences kept by built-in summaries (the conclusion), up to this many tokens
+    pub summary_tail_tokens: usize,
+    
+    /// Keep fenced code blocks verbatim when compressing; only prose is summarized
+    pub preserve_code_blocks: bool,
+    
+    /// Spans matching any of these (file paths, error codes, ...) are carried
+    /// into built-in summaries even when their sentence is dropped
+    #[serde(with = "regex_list")]
+    pub summary_preserve_patterns: Vec<Regex>,
+    
+    /// Appended to built-in summaries (`None` = no marker)
+    pub compressed_marker: Option<String>,
+    
+    /// Tag for key terms dropped by a built-in summary; `{}` is the term (`None` = no tags)
+    pub key_term_marker: Option<String>,
+    
+    /// Hard cap on retained message count, independent of tokens (`None` = unbounded)
+    pub max_messages: Option<usize>,
+    
+    /// Messages containing any of these (case-insensitive) are never removed
+    pub essential_patterns: Vec<String>,
+    
+    /// Messages starting with any of these (case-insensitive) are never removed
+    pub essential_prefixes: Vec<String>,
+    
+    /// Substrings that mark a message as containing code (word-start matches)
+    pub code_markers: Vec<String>,
+    
+    /// Importance delta for messages matching any of `code_markers`
+    pub code_boost: f64,
+    
+    /// How raw importance sums are mapped into [0, 1]
+    pub score_normalization: ScoreNormalization,
+    
+    /// Truncate any single message above this many tokens on insert (`None` = never)
+    pub max_single_message_tokens: Option<usize>,
+    
+    /// Handling of a single message larger than the whole token limit
+    pub oversized_policy: OversizedPolicy,
+    
+    /// Fraction of the limit at which compression (never removal) starts, e.g. 0.9;
+    /// 1.0 compresses only once over `max_tokens`
+    pub soft_limit_ratio: f64,
+    
+    /// Once removal triggers, free down to this fraction of the limit, e.g. 0.8,
+    /// so the next inserts don't prune again straight away; 1.0 stops just under it
+    pub prune_target_ratio: f64,
+    
+    /// Largest share of the token limit essential messages may hold before the
+    /// least important of them are compressed
+    pub essential_token_ratio: f64,
+    
+    /// Lowest final importance per role, e.g. `"system" => 0.6` (ranking only, no keep guarantee)
+    pub role_importance_floor: HashMap<String, f64>,
+    
+    /// Importance added to a message each time a later one quotes it (0.0 = off);
+    /// costs a scan of the whole history per quoting insert
+    pub reference_boost: f64,
+    
+    /// Shortest quoted span (in characters) that counts as a reference
+    pub min_reference_chars: usize,
+    
+    /// Minimum fraction of tokens a summary must save to replace the original (0.1 = 10%)
+    pub min_compression_savings: f64,
+    
+    /// Token budget of the newest, never-compressed zone (`None` = see `full_retention_ratio`)
+    pub full_zone_tokens: Option<usize>,
+    
+    /// Share of current tokens, newest first, kept uncompressed, e.g. 0.3; the
+    /// message crossing the share is included (`None` = last `full_retention_count` messages)
+    pub full_retention_ratio: Option<f64>,
+    
+    /// Token budget of the summarized zone behind it; anything older is dropped (`None` = no dropped zone)
+    pub summary_zone_tokens: Option<usize>,
+}
+
+impl Default for OptimizationConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 800_000,    // 20% less than 1M baseline
+            min_messages: 15,       // Always keep recent context
+            compression_threshold: 0.7, // Compress messages with importance < 0.7
+            enable_aggressive_pruning: true, // Enable when needed
+            enable_compression: true,
+            removal_importance_threshold: 0.3,
+            aggressive_recency_boost: 0.0,
+            compression_min_length: 200, // Short messages aren't worth summarizing
+            keyword_weights: default_keyword_weights(),
+            type_implied_keywords: default_type_implied_keywords(),
+            per_type_budgets: HashMap::new(),
+            utilization_alert_thresholds: vec![80, 90, 100],
+            always_keep_roles: HashSet::new(),
+            preserve_one_per_type: false,
+            per_message_overhead: 4,
+            recency_weight: 0.1,
+            recency_half_life_minutes: 60.0,
+            prune_strategy: PruneStrategy::Intelligent,
+            custom_type_weights: HashMap::new(),
+            dedup_comparison: DedupComparison::Exact,
+            summary_head_tokens: 30,
+            summary_tail_tokens: 20,
+            preserve_code_blocks: true,
+            summary_preserve_patterns: Vec::new(),
+            compressed_marker: Some("[Compressed]".to_string()),
+            key_term_marker: Some("[Contains: {}]".to_string()),
+            max_messages: None,
+            essential_patterns: default_essential_patterns(),
+            essential_prefixes: default_essential_prefixes(),
+            code_markers: vec!["```".to_string(), "fn ".to_string()],
+            code_boost: 0.1,
+            score_normalization: ScoreNormalization::Clamp,
+            max_single_message_tokens: None,
+            oversized_policy: OversizedPolicy::AllowAndWarn,
+            soft_limit_ratio: 1.0,
+            prune_target_ratio: 1.0,
+            essential_token_ratio: 0.5,
+            role_importance_floor: HashMap::new(),
+            reference_boost: 0.0,
+            min_reference_chars: 20,
+            min_compression_savings: 0.1,
+            full_zone_tokens: None,
+            full_retention_ratio: None,
+            summary_zone_tokens: None,
+        }
+    }
+}
+
 /// Enhanced conversation history with intelligent pruning capabilities
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OptimizedConversationHistory {
@@ -19,11 +752,76 @@ pub struct OptimizedConversationHistory {
     /// Current estimated token count
     current_tokens: usize,
     
-    /// Minimum messages to always keep (regardless of token limit)
-    min_messages: usize,
+    /// Every token ever added, before any pruning (monotonic)
+    #[serde(default)]
+    total_tokens_ever_added: usize,
     
     /// Messages to keep in full (recent + important)
     full_retention_count: usize,
+    
+    /// Token counter (not serialized; restored as the heuristic default)
+    #[serde(skip, default = "default_tokenizer")]
+    tokenizer: Arc<dyn Tokenizer>,
+    
+    /// Scoring and pruning policy
+    #[serde(default)]
+    config: OptimizationConfig,
+    
+    /// Caller-supplied closures (not serialized)
+    #[serde(skip)]
+    hooks: HistoryHooks,
+    
+    /// Set when pruning could not get under `max_tokens` (the retained floor is too big)
+    #[serde(skip)]
+    over_budget_floor: bool,
+    
+    /// How often each pruning strategy has run (not serialized)
+    #[serde(skip)]
+    telemetry: PruneTelemetry,
+    
+    /// Set when essential messages alone still exceed `essential_token_ratio` after capping
+    #[serde(skip)]
+    essential_over_cap: bool,
+    
+    /// Tokens held back for the next completion (see `reserve_headroom`)
+    #[serde(default)]
+    reserved_tokens: usize,
+    
+    /// Messages still being streamed in (not yet part of `items`)
+    #[serde(skip)]
+    streams: HashMap<StreamHandle, ResponseItem>,
+    
+    #[serde(skip)]
+    next_stream_id: u64,
+}
+
+/// Current on-disk format version written by `save_to_path`
+pub const HISTORY_FORMAT_VERSION: u32 = 1;
+
+/// Versioned JSON envelope used for persistence
+#[cfg(feature = "fs")]
+#[derive(Serialize)]
+struct HistoryEnvelopeRef<'a> {
+    format_version: u32,
+    history: &'a OptimizedConversationHistory,
+}
+
+#[cfg(feature = "fs")]
+#[derive(Deserialize)]
+struct HistoryEnvelope {
+    format_version: u32,
+    history: serde_json::Value,
+}
+
+/// Options for `load_from_path_with`
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Replace the saved config (e.g. with this deployment's scoring weights)
+    pub config: Option<OptimizationConfig>,
+    
+    /// Recompute stored importance scores after loading
+    pub rescore: bool,
 }
 
 /// Response item with enhanced metadata for intelligent pruning
@@ -31,13 +829,58 @@ pub struct OptimizedConversationHistory {
 pub struct ResponseItem {
     pub content: String,
     pub role: String,
-    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub timestamp: Timestamp,
     pub token_count: usize,
     pub importance_score: f64,
     pub message_type: MessageType,
+    
+    /// Pinned messages are never compressed or removed by pruning
+    #[serde(default)]
+    pub pinned: bool,
+    
+    /// Full text replaced by compression, recoverable via `restore`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_content: Option<String>,
+    
+    /// Content is a summary (compressed or coalesced), not the original text
+    #[serde(default)]
+    pub is_compressed: bool,
+    
+    /// Links messages that must be kept or dropped together (tool call + result)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    
+    /// Caller-defined annotations (request IDs, trace spans, ...); kept through compression
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ResponseItem {
+    /// Start building a message; the history fills in `token_count` and `importance_score`
+    ///
+    /// Defaults: timestamp now, `MessageType::ContextualInfo`, unpinned, no group.
+    pub fn builder(content: impl Into<String>, role: impl Into<String>) -> ResponseItemBuilder {
+        ResponseItemBuilder {
+            item: ResponseItem {
+                content: content.into(),
+                role: role.into(),
+                timestamp: now_timestamp(),
+                token_count: 0,
+                importance_score: 0.0,
+                message_type: MessageType::ContextualInfo,
+                pinned: false,
+                original_content: None,
+                is_compressed: false,
+                group_id: None,
+                metadata: HashMap::new(),
+            },
+        }
+    }
+}
+
+/// Serialized as a plain string: the variant name, or the tag for `Custom`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum MessageType {
     UserQuery,
     SystemResponse,
@@ -45,279 +888,3798 @@ pub enum MessageType {
     ImportantDecision,
     ErrorHandling,
     ContextualInfo,
+    /// Caller-defined category (e.g. "ToolCall", "Reflection"), weighted via
+    /// `OptimizationConfig::custom_type_weights`
+    Custom(String),
+}
+
+impl MessageType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::UserQuery => "UserQuery",
+            Self::SystemResponse => "SystemResponse",
+            Self::CodeExecution => "CodeExecution",
+            Self::ImportantDecision => "ImportantDecision",
+            Self::ErrorHandling => "ErrorHandling",
+            Self::ContextualInfo => "ContextualInfo",
+            Self::Custom(tag) => tag,
+        }
+    }
+}
+
+impl From<String> for MessageType {
+    /// Built-in names map to their variants; anything else becomes `Custom`
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "UserQuery" => Self::UserQuery,
+            "SystemResponse" => Self::SystemResponse,
+            "CodeExecution" => Self::CodeExecution,
+            "ImportantDecision" => Self::ImportantDecision,
+            "ErrorHandling" => Self::ErrorHandling,
+            "ContextualInfo" => Self::ContextualInfo,
+            _ => Self::Custom(name),
+        }
+    }
+}
+
+impl From<MessageType> for String {
+    fn from(message_type: MessageType) -> Self {
+        match message_type {
+            MessageType::Custom(tag) => tag,
+            other => other.as_str().to_string(),
+        }
+    }
 }
 
 impl OptimizedConversationHistory {
     /// Create new optimized conversation history
     pub fn new(max_tokens: usize) -> Self {
+        Self::with_tokenizer(max_tokens, HeuristicTokenizer)
+    }
+    
+    /// Create a history sized for `model`, leaving room for its completion
+    pub fn for_model(model: ModelProfile) -> Self {
+        Self::new(model.max_tokens())
+    }
+    
+    /// Like `new`, but rejects a `max_tokens` below `MIN_MAX_TOKENS`
+    pub fn try_new(max_tokens: usize) -> Result<Self, ConfigError> {
+        if max_tokens < MIN_MAX_TOKENS {
+            return Err(ConfigError::MaxTokensTooSmall { max_tokens, min: MIN_MAX_TOKENS });
+        }
+        Ok(Self::new(max_tokens))
+    }
+    
+    /// Create optimized conversation history with a custom token counter
+    pub fn with_tokenizer(max_tokens: usize, tokenizer: impl Tokenizer + 'static) -> Self {
         Self {
             items: VecDeque::new(),
             max_tokens,
             current_tokens: 0,
-            min_messages: 10,  // Always keep last 10 messages
+            total_tokens_ever_added: 0,
             full_retention_count: 20,  // Keep last 20 in full detail
+            tokenizer: Arc::new(tokenizer),
+            config: OptimizationConfig {
+                max_tokens,
+                min_messages: 10,  // Always keep last 10 messages
+                ..OptimizationConfig::default()
+            },
+            hooks: HistoryHooks::default(),
+            over_budget_floor: false,
+            essential_over_cap: false,
+            telemetry: PruneTelemetry::default(),
+            reserved_tokens: 0,
+            streams: HashMap::new(),
+            next_stream_id: 0,
+        }
+    }
+    
+    /// Create optimized conversation history from a configuration
+    ///
+    /// `max_tokens` and `min_messages` are taken from the config.
+    pub fn with_config(config: OptimizationConfig) -> Self {
+        let mut history = Self::new(config.max_tokens);
+        history.apply_config(config);
+        history
+    }
+    
+    /// Install `config`, taking `max_tokens` from it
+    fn apply_config(&mut self, mut config: OptimizationConfig) {
+        // Fold keywords once so scoring can compare against lowercased content
+        config.keyword_weights = config
+            .keyword_weights
+            .into_iter()
+            .map(|(keyword, weight)| (keyword.to_lowercase(), weight))
+            .collect();
+        for marker in config.essential_patterns.iter_mut()
+            .chain(&mut config.essential_prefixes)
+            .chain(config.type_implied_keywords.values_mut().flatten())
+        {
+            *marker = marker.to_lowercase();
         }
+        
+        self.max_tokens = config.max_tokens;
+        self.config = config;
+    }
+    
+    /// Recompute every item's importance with the current config and clock
+    ///
+    /// Use after changing scoring weights, or on histories loaded from disk
+    /// whose stored scores came from older scoring logic.
+    pub fn rescore_all(&mut self) {
+        self.rescore_where(|_| true);
+        for i in 0..self.items.len() {
+            self.boost_referenced_by(i);
+        }
+    }
+    
+    /// Recompute importance only for messages matching `pred`
+    ///
+    /// Cheaper than `rescore_all` when a config change affects a known subset,
+    /// e.g. one role's floor or one custom type's weight.
+    pub fn rescore_where(&mut self, pred: impl Fn(&ResponseItem) -> bool) {
+        let now = self.now();
+        let mut content_lower = String::new();
+        let scores: Vec<(usize, f64)> = self.items.iter()
+            .enumerate()
+            .filter(|(_, item)| pred(item))
+            .map(|(i, item)| {
+                lowercase_into(&mut content_lower, &item.content);
+                (i, self.importance_breakdown_lowered(item, now, &content_lower).score)
+            })
+            .collect();
+        for (i, score) in scores {
+            self.items[i].importance_score = score;
+        }
+    }
+    
+    /// Start building a history with custom retention settings
+    ///
+    /// Defaults: 800K tokens, `min_messages` 10, `full_retention_count` 20.
+    pub fn builder() -> OptimizedConversationHistoryBuilder {
+        OptimizedConversationHistoryBuilder {
+            history: Self::new(OptimizationConfig::default().max_tokens),
+        }
+    }
+    
+    /// Change the number of recent messages always kept, re-pruning if needed
+    pub fn set_min_messages(&mut self, min_messages: usize) -> PruneOutcome {
+        self.config.min_messages = min_messages;
+        self.intelligent_prune()
+    }
+    
+    /// Change the number of recent messages kept uncompressed
+    pub fn set_full_retention_count(&mut self, full_retention_count: usize) {
+        self.full_retention_count = full_retention_count;
+    }
+    
+    /// Add new message with automatic pruning
+    ///
+    /// A message older than the newest one is inserted at its chronological
+    /// position, so the history stays ordered; pruning asserts this invariant
+    /// in debug builds.
+    pub fn add_message(&mut self, item: ResponseItem) {
+        self.add_message_reporting(item);
+    }
+    
+    /// Add new message and report what pruning removed or compressed
+    ///
+    /// A message refused by `OversizedPolicy::Reject` is dropped with a
+    /// warning and counted in `PruneOutcome::rejected`.
+    pub fn add_message_reporting(&mut self, item: ResponseItem) -> PruneOutcome {
+        match self.try_add_message(item) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                log::warn!("Dropped message: {}", err);
+                PruneOutcome { rejected: 1, ..PruneOutcome::default() }
+            }
+        }
+    }
+    
+    /// Fallible `add_message_reporting`: errors instead of dropping a message
+    /// refused by `OversizedPolicy::Reject` (nothing is added or pruned)
+    pub fn try_add_message(&mut self, item: ResponseItem) -> Result<PruneOutcome, OversizedError> {
+        let truncated = self.insert_item(item)?;
+        
+        // Prune if necessary
+        let mut outcome = self.intelligent_prune();
+        outcome.truncated += usize::from(truncated);
+        Ok(outcome)
+    }
+    
+    /// Add many messages at once, pruning a single time at the end
+    ///
+    /// Much cheaper than calling `add_message` in a loop when ingesting a
+    /// long transcript, since each `add_message` may run a full prune pass.
+    /// Imported messages older than the current newest are slotted in by timestamp.
+    pub fn add_messages(&mut self, items: Vec<ResponseItem>) -> PruneOutcome {
+        self.items.reserve(items.len());
+        let mut truncated = 0;
+        let mut rejected = 0;
+        for item in items {
+            match self.insert_item(item) {
+                Ok(was_truncated) => truncated += usize::from(was_truncated),
+                Err(err) => {
+                    log::warn!("Dropped message: {}", err);
+                    rejected += 1;
+                }
+            }
+        }
+        
+        if !self.is_chronologically_ordered() {
+            // Stable: equal timestamps keep their insertion order
+            self.items.make_contiguous().sort_by_key(|item| item.timestamp);
+        }
+        
+        let mut outcome = self.intelligent_prune();
+        outcome.truncated += truncated;
+        outcome.rejected += rejected;
+        outcome
+    }
+    
+    /// Add new message, compressing through an async summarizer if pruning is needed
+    pub async fn add_message_async<F, Fut>(&mut self, item: ResponseItem, summarizer: F) -> PruneOutcome
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = String>,
+    {
+        let truncated = match self.insert_item(item) {
+            Ok(truncated) => truncated,
+            Err(err) => {
+                log::warn!("Dropped message: {}", err);
+                return PruneOutcome { rejected: 1, ..PruneOutcome::default() };
+            }
+        };
+        let mut outcome = self.intelligent_prune_async(summarizer).await;
+        outcome.truncated += usize::from(truncated);
+        outcome
+    }
+    
+    /// Replace the content of the message at `index` (e.g. a regenerated answer)
+    ///
+    /// Re-counts and re-scores the message, keeps `current_tokens` in sync and
+    /// prunes if the new content pushes the history over budget. Any stored
+    /// pre-compression original is dropped as stale.
+    pub fn replace_message(&mut self, index: usize, new_content: String) -> Result<(), IndexError> {
+        let len = self.items.len();
+        let mut item = self.items.get(index).cloned().ok_or(IndexError { index, len })?;
+        let old_cost = self.item_cost(&item);
+        
+        item.token_count = self.estimate_tokens(&new_content);
+        item.content = new_content;
+        item.original_content = None;
+        item.is_compressed = false;
+        self.truncate_oversized(&mut item);
+        item.importance_score = self.calculate_importance(&item, self.now());
+        
+        let new_cost = self.item_cost(&item);
+        self.replace_tokens(old_cost, new_cost);
+        self.total_tokens_ever_added += new_cost;
+        self.items[index] = item;
+        
+        self.intelligent_prune();
+        Ok(())
+    }
+    
+    /// Change the type of the message at `index` and re-score it
+    ///
+    /// For classifications made on partial content, e.g. a streamed reply
+    /// that only turned out to report an error at the end.
+    pub fn reclassify(&mut self, index: usize, message_type: MessageType) -> Result<(), IndexError> {
+        let len = self.items.len();
+        let now = self.now();
+        let item = self.items.get_mut(index).ok_or(IndexError { index, len })?;
+        item.message_type = message_type;
+        let score = self.calculate_importance(&self.items[index], now);
+        self.items[index].importance_score = score;
+        Ok(())
+    }
+    
+    /// Merge another history's messages into this one by timestamp, then prune once
+    ///
+    /// This history's limits and config win; incoming messages are re-scored
+    /// with them. On equal timestamps this history's messages come first.
+    pub fn merge(&mut self, other: OptimizedConversationHistory) -> PruneOutcome {
+        let now = self.now();
+        let mut ours = std::mem::take(&mut self.items).into_iter().peekable();
+        let mut theirs = other.items.into_iter().peekable();
+        let mut merged = VecDeque::with_capacity(ours.len() + theirs.len());
+        
+        loop {
+            let take_ours = match (ours.peek(), theirs.peek()) {
+                (Some(a), Some(b)) => a.timestamp <= b.timestamp,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            
+            if take_ours {
+                merged.extend(ours.next());
+            } else if let Some(mut item) = theirs.next() {
+                // The other history may count with a different tokenizer
+                item.token_count = self.estimate_tokens(&item.content);
+                item.importance_score = self.calculate_importance(&item, now);
+                let cost = self.item_cost(&item);
+                self.current_tokens += cost;
+                self.total_tokens_ever_added += cost;
+                merged.push_back(item);
+            }
+        }
+        
+        self.items = merged;
+        self.intelligent_prune()
+    }
+    
+    /// Score and insert an item (in timestamp order) without pruning; returns
+    /// whether it was truncated
+    ///
+    /// Fails, adding nothing, if the item alone exceeds the limit under
+    /// `OversizedPolicy::Reject`.
+    fn insert_item(&mut self, mut item: ResponseItem) -> Result<bool, OversizedError> {
+        // Calculate token count if not provided
+        if item.token_count == 0 {
+            item.token_count = self.estimate_tokens(&item.content);
+        }
+        
+        // Oversized messages count at full size toward the unpruned baseline
+        let added = self.item_cost(&item);
+        let truncated = self.truncate_oversized(&mut item);
+        
+        let cost = self.item_cost(&item);
+        let limit = self.working_limit();
+        if cost > limit {
+            match self.config.oversized_policy {
+                OversizedPolicy::Reject => return Err(OversizedError { tokens: cost, max_tokens: limit }),
+                OversizedPolicy::Truncate | OversizedPolicy::AllowAndWarn => log::warn!(
+                    "Message of {} tokens exceeds the {} token limit on its own; history will stay over budget",
+                    cost, limit
+                ),
+            }
+        }
+        
+        // Calculate importance score
+        item.importance_score = self.calculate_importance(&item, self.now());
+        
+        // Add the new item at its chronological position (normally the end)
+        let pct_before = self.utilization_pct();
+        self.current_tokens += self.item_cost(&item);
+        self.total_tokens_ever_added += added;
+        let at = self.items.partition_point(|existing| existing.timestamp <= item.timestamp);
+        self.items.insert(at, item);
+        self.boost_referenced_by(at);
+        
+        self.emit_threshold_crossings(pct_before);
+        Ok(truncated)
+    }
+    
+    /// Start accumulating a streamed message
+    ///
+    /// The message joins the history (and counts toward `current_tokens`)
+    /// only on `finish_streaming`.
+    pub fn begin_streaming(&mut self, role: impl Into<String>, message_type: MessageType) -> StreamHandle {
+        let handle = StreamHandle(self.next_stream_id);
+        self.next_stream_id += 1;
+        
+        let item = ResponseItem::builder(String::new(), role)
+            .message_type(message_type)
+            .timestamp(self.now())
+            .build();
+        self.streams.insert(handle, item);
+        handle
+    }
+    
+    /// Append a streamed chunk; returns `false` for an unknown handle
+    pub fn push_delta(&mut self, handle: StreamHandle, chunk: &str) -> bool {
+        let chunk_tokens = self.estimate_tokens(chunk);
+        match self.streams.get_mut(&handle) {
+            Some(item) => {
+                item.content.push_str(chunk);
+                item.token_count += chunk_tokens;
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Running token estimate of an in-progress stream
+    pub fn streaming_tokens(&self, handle: StreamHandle) -> Option<usize> {
+        self.streams.get(&handle).map(|item| item.token_count)
+    }
+    
+    /// Complete a stream: add the message (timestamped now) and prune once
+    ///
+    /// Returns `None` for an unknown handle.
+    pub fn finish_streaming(&mut self, handle: StreamHandle) -> Option<PruneOutcome> {
+        let mut item = self.streams.remove(&handle)?;
+        
+        // Per-chunk estimates drift at chunk boundaries; count the final text once
+        item.token_count = self.estimate_tokens(&item.content);
+        item.timestamp = self.now();
+        
+        Some(self.add_message_reporting(item))
+    }
+    
+    /// Like `finish_streaming`, but first corrects the type chosen at
+    /// `begin_streaming` now that the full content is known
+    pub fn finish_streaming_as(&mut self, handle: StreamHandle, message_type: MessageType) -> Option<PruneOutcome> {
+        self.streams.get_mut(&handle)?.message_type = message_type;
+        self.finish_streaming(handle)
+    }
+    
+    /// Register a callback fired on every removal, compression and threshold crossing
+    pub fn on_prune(&mut self, callback: impl FnMut(PruneEvent) + Send + 'static) {
+        self.hooks.prune_subscribers.push(Arc::new(Mutex::new(callback)));
+    }
+    
+    /// Deliver `event` to every subscriber
+    fn emit(&self, event: PruneEvent) {
+        for subscriber in &self.hooks.prune_subscribers {
+            // A panicking subscriber must not silence the others
+            let mut callback = subscriber.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            callback(event.clone());
+        }
+    }
+    
+    /// Push-based `get_stats`: a snapshot after every add and prune pass
+    ///
+    /// Backed by a broadcast channel; a subscriber more than 16 snapshots
+    /// behind skips the ones it missed. Clones share subscribers.
+    #[cfg(feature = "tokio")]
+    pub fn stats_stream(&self) -> impl tokio_stream::Stream<Item = ConversationStats> {
+        use tokio_stream::StreamExt;
+        
+        let receiver = self.stats_sender().subscribe();
+        tokio_stream::wrappers::BroadcastStream::new(receiver).filter_map(Result::ok)
+    }
+    
+    #[cfg(feature = "tokio")]
+    fn stats_sender(&self) -> &tokio::sync::broadcast::Sender<ConversationStats> {
+        self.hooks.stats_channel.get_or_init(|| tokio::sync::broadcast::channel(STATS_CHANNEL_CAPACITY).0)
+    }
+    
+    /// Send a snapshot to `stats_stream` subscribers (skipped when there are none)
+    #[cfg(feature = "tokio")]
+    fn publish_stats(&self) {
+        if let Some(sender) = self.hooks.stats_channel.get() {
+            if sender.receiver_count() > 0 {
+                // Only fails if every receiver dropped since the check
+                let _ = sender.send(self.get_stats());
+            }
+        }
+    }
+    
+    #[cfg(not(feature = "tokio"))]
+    fn publish_stats(&self) {}
+    
+    fn emit_removed(&self, item: &ResponseItem) {
+        if !self.hooks.prune_subscribers.is_empty() {
+            self.emit(PruneEvent::Removed {
+                message_type: item.message_type.clone(),
+                tokens: self.item_cost(item),
+            });
+        }
+    }
+    
+    /// Emit `ThresholdCrossed` for each alert threshold passed since `pct_before`
+    fn emit_threshold_crossings(&self, pct_before: u32) {
+        if self.hooks.prune_subscribers.is_empty() {
+            return;
+        }
+        
+        let pct_after = self.utilization_pct();
+        for &pct in &self.config.utilization_alert_thresholds {
+            if pct_before < pct && pct <= pct_after {
+                self.emit(PruneEvent::ThresholdCrossed { pct });
+            }
+        }
+    }
+    
+    fn utilization_pct(&self) -> u32 {
+        (self.utilization() * 100.0) as u32
+    }
+    
+    /// Use `summarizer` instead of the built-in first-sentence summary when compressing
+    pub fn with_summarizer(mut self, summarizer: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        self.hooks.summarizer = Some(Arc::new(summarizer));
+        self
+    }
+    
+    /// Use `clock` as the time source for recency scoring (e.g. a frozen clock in tests)
+    pub fn with_clock(mut self, clock: impl Fn() -> Timestamp + Send + Sync + 'static) -> Self {
+        self.hooks.clock = Some(Arc::new(clock));
+        self
+    }
+    
+    /// Veto evictions: every prune strategy keeps messages for which `filter` returns `true`
+    ///
+    /// E.g. keep anything whose metadata references an active task.
+    pub fn set_retention_filter(&mut self, filter: impl Fn(&ResponseItem) -> bool + Send + Sync + 'static) {
+        self.hooks.retention_filter = Some(Arc::new(filter));
+    }
+    
+    /// Current time from the injected clock, or the system clock
+    fn now(&self) -> Timestamp {
+        match &self.hooks.clock {
+            Some(clock) => clock(),
+            None => now_timestamp(),
+        }
+    }
+    
+    /// Replace the token counter (e.g. after `load_from_path`)
+    ///
+    /// Every retained message is re-counted with it, so counts taken at insert
+    /// and after compression stay on the same scale.
+    pub fn set_tokenizer(&mut self, tokenizer: impl Tokenizer + 'static) {
+        self.tokenizer = Arc::new(tokenizer);
+        self.recount_all();
+    }
+    
+    /// Re-count every message with the current tokenizer and re-sum `current_tokens`
+    fn recount_all(&mut self) {
+        let tokenizer = Arc::clone(&self.tokenizer);
+        for item in &mut self.items {
+            item.token_count = tokenizer.count(&item.content);
+        }
+        self.recompute_tokens();
+    }
+    
+    /// Pin the message at `index` so pruning never compresses or removes it
+    ///
+    /// Returns `false` if `index` is out of range.
+    pub fn pin_message(&mut self, index: usize) -> bool {
+        self.set_pinned(index, true)
+    }
+    
+    /// Unpin the message at `index`, making it eligible for pruning again
+    ///
+    /// Returns `false` if `index` is out of range.
+    pub fn unpin_message(&mut self, index: usize) -> bool {
+        self.set_pinned(index, false)
+    }
+    
+    fn set_pinned(&mut self, index: usize, pinned: bool) -> bool {
+        match self.items.get_mut(index) {
+            Some(item) => {
+                item.pinned = pinned;
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Intelligent pruning based on token limits and importance
+    ///
+    /// Dispatches on `OptimizationConfig::prune_strategy`.
+    pub fn intelligent_prune(&mut self) -> PruneOutcome {
+        let outcome = self.prune_pass();
+        self.publish_stats();
+        outcome
+    }
+    
+    /// `intelligent_prune` without the `stats_stream` snapshot
+    fn prune_pass(&mut self) -> PruneOutcome {
+        let mut outcome = PruneOutcome::default();
+        
+        // Message-count cap applies under every strategy
+        self.enforce_max_messages(&mut outcome);
+        
+        match self.config.prune_strategy {
+            PruneStrategy::SlidingWindow => self.slide_window(&mut outcome),
+            PruneStrategy::CompressOnly => {
+                if self.config.enable_compression && self.current_tokens > self.soft_limit() {
+                    self.compress_old_messages(&mut outcome);
+                }
+            }
+            PruneStrategy::Intelligent => {
+                // Per-type budgets apply even when under the global limit
+                self.enforce_type_budgets(&mut outcome);
+                self.enforce_zones(&mut outcome);
+                
+                // Compression starts at the soft limit; removal only past the hard one
+                if self.current_tokens > self.soft_limit() {
+                    // Strategy 1: Compress older messages (keep summary)
+                    if self.config.enable_compression {
+                        self.compress_old_messages(&mut outcome);
+                    }
+                    
+                    self.prune_after_compression(&mut outcome);
+                }
+            }
+            PruneStrategy::Coalesce => {
+                self.enforce_type_budgets(&mut outcome);
+                self.enforce_zones(&mut outcome);
+                
+                if self.current_tokens > self.soft_limit() {
+                    if self.config.enable_compression {
+                        self.coalesce_runs(&mut outcome);
+                    }
+                    self.prune_after_compression(&mut outcome);
+                }
+            }
+        }
+        
+        self.check_budget_floor();
+        outcome
+    }
+    
+    /// Async counterpart of `intelligent_prune` for LLM-backed summarizers
+    ///
+    /// Only the compression step awaits; removal strategies run synchronously.
+    pub async fn intelligent_prune_async<F, Fut>(&mut self, summarizer: F) -> PruneOutcome
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = String>,
+    {
+        let mut outcome = PruneOutcome::default();
+        
+        self.enforce_max_messages(&mut outcome);
+        
+        let strategy = self.config.prune_strategy;
+        if strategy == PruneStrategy::SlidingWindow {
+            self.slide_window(&mut outcome);
+            self.check_budget_floor();
+            self.publish_stats();
+            return outcome;
+        }
+        
+        let removes = matches!(strategy, PruneStrategy::Intelligent | PruneStrategy::Coalesce);
+        if removes {
+            self.enforce_type_budgets(&mut outcome);
+            self.enforce_zones(&mut outcome);
+        }
+        
+        if self.current_tokens <= self.soft_limit() {
+            self.check_budget_floor();
+            self.publish_stats();
+            return outcome;
+        }
+        
+        if self.config.enable_compression {
+            if strategy == PruneStrategy::Coalesce {
+                // Coalescing summarizes through the sync path
+                self.coalesce_runs(&mut outcome);
+            } else {
+                // Strategy 1: Compress older messages through the async summarizer,
+                // which (like `summarize`) only ever sees the prose
+                self.telemetry.compress_invocations += 1;
+                for i in self.compression_candidates() {
+                    let content = &self.items[i].content;
+                    let summary = if self.config.preserve_code_blocks && content.contains("```") {
+                        let (prose, code_blocks) = split_code_fences(content);
+                        with_code_blocks(summarizer(prose.trim().to_string()).await, &code_blocks)
+                    } else {
+                        summarizer(content.clone()).await
+                    };
+                    self.apply_compression(i, summary, &mut outcome);
+                }
+            }
+        }
+        
+        if removes {
+            self.prune_after_compression(&mut outcome);
+        }
+        
+        self.check_budget_floor();
+        self.publish_stats();
+        outcome
+    }
+    
+    /// Record (and warn once) when pruning cannot reach `max_tokens`
+    ///
+    /// Happens when pinned, protected and `min_messages` recent messages alone
+    /// exceed the limit; every later insert would prune to no effect.
+    fn check_budget_floor(&mut self) {
+        let stuck = self.current_tokens > self.working_limit();
+        if stuck && !self.over_budget_floor {
+            log::warn!(
+                "History stuck at {} tokens over the {} token limit: retained messages exceed the budget",
+                self.current_tokens, self.working_limit()
+            );
+        }
+        self.over_budget_floor = stuck;
+        if !stuck {
+            self.essential_over_cap = false;
+        }
+    }
+    
+    /// Plain sliding window: once over `max_tokens`, drop the oldest messages
+    /// until under the prune target
+    ///
+    /// Ignores importance; pinned messages, `always_keep_roles` and messages
+    /// the retention filter vetoes are skipped rather than evicted.
+    fn slide_window(&mut self, outcome: &mut PruneOutcome) {
+        if self.current_tokens <= self.working_limit() {
+            return;
+        }
+        
+        let target = self.prune_target();
+        let mut projected_tokens = self.current_tokens;
+        let mut remove = vec![false; self.items.len()];
+        
+        for (i, item) in self.items.iter().enumerate() {
+            if projected_tokens <= target {
+                break;
+            }
+            if !self.is_protected(item) {
+                remove[i] = true;
+                projected_tokens = projected_tokens.saturating_sub(self.item_cost(item));
+            }
+        }
+        
+        self.remove_marked(remove, outcome);
+        self.debug_assert_ordered();
+    }
+    
+    /// Preview what `intelligent_prune` would do right now, without mutating
+    ///
+    /// Runs the real pruning logic on a clone (subscribers are not notified,
+    /// but a custom summarizer is still called).
+    pub fn simulate_prune(&self) -> PruneReport {
+        let mut sandbox = self.clone();
+        sandbox.hooks.prune_subscribers.clear();
+        #[cfg(feature = "tokio")]
+        {
+            sandbox.hooks.stats_channel = std::sync::OnceLock::new();
+        }
+        let outcome = sandbox.intelligent_prune();
+        PruneReport::from_outcome(&outcome, self.items.len(), sandbox.current_tokens)
+    }
+    
+    /// Prune down to `target_tokens` right now, e.g. before switching to a
+    /// smaller-context model, then restore the configured limit
+    ///
+    /// Runs the configured strategy against the temporary limit, so
+    /// `CompressOnly` may stop above the target.
+    pub fn trim_to(&mut self, target_tokens: usize) -> PruneReport {
+        let len_before = self.items.len();
+        let max_tokens = std::mem::replace(&mut self.max_tokens, target_tokens);
+        let reserved_tokens = std::mem::take(&mut self.reserved_tokens);
+        
+        // Unpublished: subscribers see only the final snapshot, under the real limit
+        let outcome = self.prune_pass();
+        
+        self.max_tokens = max_tokens;
+        self.reserved_tokens = reserved_tokens;
+        self.check_budget_floor();
+        self.publish_stats();
+        PruneReport::from_outcome(&outcome, len_before, self.current_tokens)
+    }
+    
+    /// Strategies 2 and 3, shared by the sync and async prune paths
+    ///
+    /// Triggers only past the hard limit, then frees down to the prune target.
+    fn prune_after_compression(&mut self, outcome: &mut PruneOutcome) {
+        if self.current_tokens <= self.working_limit() {
+            return;
+        }
+        
+        // Essential messages are never removed, so keep their share bounded
+        if self.config.enable_compression {
+            self.cap_essential_tokens(outcome);
+        }
+        
+        // Strategy 2: Remove low-importance middle messages
+        if self.current_tokens > self.prune_target() {
+            self.remove_low_importance_messages(outcome);
+        }
+        
+        // Strategy 3: Aggressive pruning (keep only essentials)
+        if self.current_tokens > self.prune_target() {
+            self.aggressive_prune(outcome);
+        }
+    }
+    
+    /// Compress older messages to summaries
+    fn compress_old_messages(&mut self, outcome: &mut PruneOutcome) {
+        self.telemetry.compress_invocations += 1;
+        for i in self.compression_candidates() {
+            let summary = self.summarize(&self.items[i].content);
+            self.apply_compression(i, summary, outcome);
+        }
+        self.debug_assert_ordered();
+    }
+    
+    /// Merge each run of consecutive low-importance older messages into one summary
+    ///
+    /// Runs are taken from outside the full-fidelity zone (see `zones`); protected,
+    /// essential and grouped messages break a run. Besides shrinking content,
+    /// this saves the per-message overhead of every merged message.
+    pub fn coalesce_old_messages(&mut self) -> PruneOutcome {
+        let mut outcome = PruneOutcome::default();
+        self.coalesce_runs(&mut outcome);
+        outcome
+    }
+    
+    // 🧩 Individual pruning steps, for composing custom policies. Each runs on
+    // its own, ignoring `prune_strategy`; `trim_to` lowers the limit first.
+    
+    /// Compress every eligible older message (strategy 1), regardless of the limit
+    pub fn compress_now(&mut self) -> PruneOutcome {
+        let mut outcome = PruneOutcome::default();
+        self.compress_old_messages(&mut outcome);
+        outcome
+    }
+    
+    /// Remove low-importance older messages until under the prune target (strategy 2)
+    pub fn remove_low_importance(&mut self) -> PruneOutcome {
+        let mut outcome = PruneOutcome::default();
+        self.remove_low_importance_messages(&mut outcome);
+        outcome
+    }
+    
+    /// Keep only protected, recent and the most important messages that fit
+    /// under the prune target (strategy 3)
+    pub fn prune_aggressively(&mut self) -> PruneOutcome {
+        let mut outcome = PruneOutcome::default();
+        self.aggressive_prune(&mut outcome);
+        outcome
+    }
+    
+    fn coalesce_runs(&mut self, outcome: &mut PruneOutcome) {
+        self.telemetry.compress_invocations += 1;
+        let len = self.items.len();
+        let zone_end = self.full_zone_start();
+        
+        let mut runs = Vec::new();
+        let mut run_start = None;
+        for i in 0..=zone_end {
+            let eligible = i < zone_end && self.is_coalescable(&self.items[i]);
+            match (eligible, run_start) {
+                (true, None) => run_start = Some(i),
+                (false, Some(start)) => {
+                    if i - start >= 2 {
+                        runs.push(start..i);
+                    }
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        
+        let mut remove = vec![false; len];
+        let mut growth = 0;
+        for run in runs {
+            let merged = self.merge_run(run.clone());
+            let run_cost: usize = self.items.range(run.clone()).map(|item| self.item_cost(item)).sum();
+            if !self.worth_compressing(run_cost, self.item_cost(&merged)) {
+                continue;
+            }
+            
+            let head = run.start;
+            let old_tokens = self.items[head].token_count;
+            let new_tokens = merged.token_count;
+            outcome.compressed.push((outcome.origin_of(head), merged.original_content.clone().unwrap_or_default()));
+            self.replace_tokens(old_tokens, new_tokens);
+            outcome.tokens_freed += old_tokens.saturating_sub(new_tokens);
+            growth += new_tokens.saturating_sub(old_tokens);
+            self.items[head] = merged;
+            self.emit(PruneEvent::Compressed { index: head, tokens_before: old_tokens, tokens_after: new_tokens });
+            
+            remove[head + 1..run.end].fill(true);
+        }
+        
+        self.remove_marked(remove, outcome);
+        // The merged head can outgrow the message it replaced; net that out
+        outcome.tokens_freed = outcome.tokens_freed.saturating_sub(growth);
+        self.debug_assert_ordered();
+    }
+    
+    fn is_coalescable(&self, item: &ResponseItem) -> bool {
+        !self.is_protected(item)
+            && !self.is_essential_message(item)
+            && item.group_id.is_none()
+            && item.importance_score < self.config.compression_threshold
+    }
+    
+    /// One summary item standing in for `run`, typed after its largest message type
+    fn merge_run(&self, run: std::ops::Range<usize>) -> ResponseItem {
+        let members: Vec<&ResponseItem> = self.items.range(run).collect();
+        
+        let mut type_tokens: Vec<(&MessageType, usize)> = Vec::new();
+        for item in &members {
+            match type_tokens.iter_mut().find(|(message_type, _)| *message_type == &item.message_type) {
+                Some((_, tokens)) => *tokens += item.token_count,
+                None => type_tokens.push((&item.message_type, item.token_count)),
+            }
+        }
+        // `max_by_key` keeps the last maximum; reverse so ties go to the earliest type
+        let dominant = type_tokens.iter().rev().max_by_key(|(_, tokens)| *tokens).map(|(message_type, _)| *message_type);
+        let lead = members.iter()
+            .find(|item| Some(&item.message_type) == dominant)
+            .unwrap_or(&members[0]);
+        
+        // Label lines only where the speaker differs from the merged item's role
+        let line = |item: &ResponseItem, text: String| {
+            if item.role == lead.role { text } else { format!("{}: {}", item.role, text) }
+        };
+        let content = members.iter()
+            .map(|item| line(item, self.summarize(&item.content)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let original = members.iter()
+            .map(|item| line(item, item.original_content.clone().unwrap_or_else(|| item.content.clone())))
+            .collect::<Vec<_>>()
+            .join("\n");
+        
+        ResponseItem {
+            token_count: self.estimate_tokens(&content),
+            content,
+            role: lead.role.clone(),
+            timestamp: members[0].timestamp,
+            importance_score: members.iter().map(|item| item.importance_score).fold(0.0, f64::max),
+            message_type: lead.message_type.clone(),
+            pinned: false,
+            original_content: Some(original),
+            is_compressed: true,
+            group_id: None,
+            metadata: members.iter().flat_map(|item| item.metadata.clone()).collect(),
+        }
+    }
+    
+    /// Indices of older messages eligible for compression
+    fn compression_candidates(&self) -> Vec<usize> {
+        let compress_threshold = self.full_zone_start();
+        
+        (0..compress_threshold)
+            .filter(|&i| {
+                let item = &self.items[i];
+                !item.pinned
+                    && item.content.chars().count() > self.config.compression_min_length
+                    && item.importance_score < self.config.compression_threshold
+            })
+            .collect()
+    }
+    
+    /// Whether shrinking `before` tokens to `after` saves at least `min_compression_savings`
+    fn worth_compressing(&self, before: usize, after: usize) -> bool {
+        (after as f64) <= before as f64 * (1.0 - self.config.min_compression_savings)
+    }
+    
+    /// Replace the content at `i` with `summary`, keeping token bookkeeping in sync
+    ///
+    /// Leaves the message untouched if the summary doesn't save enough tokens.
+    fn apply_compression(&mut self, i: usize, summary: String, outcome: &mut PruneOutcome) {
+        let new_tokens = self.estimate_tokens(&summary);
+        if !self.worth_compressing(self.items[i].token_count, new_tokens) {
+            return;
+        }
+        
+        let item = &mut self.items[i];
+        let old_tokens = item.token_count;
+        let original = std::mem::replace(&mut item.content, summary);
+        item.token_count = new_tokens;
+        
+        // Keep the first original if a summary is compressed again
+        outcome.compressed.push((outcome.origin_of(i), original.clone()));
+        item.original_content.get_or_insert(original);
+        item.is_compressed = true;
+        
+        self.replace_tokens(old_tokens, new_tokens);
+        outcome.tokens_freed += old_tokens.saturating_sub(new_tokens);
+        
+        self.emit(PruneEvent::Compressed { index: i, tokens_before: old_tokens, tokens_after: new_tokens });
+    }
+    
+    /// Swap a compressed message back to its original content
+    ///
+    /// Fails without modifying anything if the restored message would push
+    /// the history over `max_tokens`.
+    pub fn restore(&mut self, index: usize) -> Result<(), RestoreError> {
+        let item = self.items.get(index).ok_or(RestoreError::IndexOutOfRange(index))?;
+        let original = item.original_content.as_deref().ok_or(RestoreError::NotCompressed(index))?;
+        
+        let original_tokens = self.estimate_tokens(original);
+        let needed = self.current_tokens.saturating_sub(item.token_count) + original_tokens;
+        if needed > self.working_limit() {
+            return Err(RestoreError::OverBudget { needed, max_tokens: self.working_limit() });
+        }
+        
+        self.replace_tokens(self.items[index].token_count, original_tokens);
+        let item = &mut self.items[index];
+        item.content = item.original_content.take().unwrap_or_default();
+        item.is_compressed = false;
+        item.token_count = original_tokens;
+        Ok(())
+    }
+    
+    /// Compress the least-important essential messages until essentials fit
+    /// `essential_token_ratio` of the limit
+    ///
+    /// Ignores `compression_threshold`; pinned, already-compressed and
+    /// full-zone messages are left alone.
+    fn cap_essential_tokens(&mut self, outcome: &mut PruneOutcome) {
+        let cap = (self.working_limit() as f64 * self.config.essential_token_ratio) as usize;
+        let essential: Vec<usize> = (0..self.items.len())
+            .filter(|&i| self.is_essential_message(&self.items[i]))
+            .collect();
+        let mut total: usize = essential.iter().map(|&i| self.item_cost(&self.items[i])).sum();
+        if total <= cap {
+            self.essential_over_cap = false;
+            return;
+        }
+        
+        let full_start = self.full_zone_start();
+        let mut candidates: Vec<usize> = essential.into_iter()
+            .filter(|&i| {
+                let item = &self.items[i];
+                i < full_start && !item.pinned && !item.is_compressed
+            })
+            .collect();
+        candidates.sort_by(|&a, &b| {
+            self.items[a].importance_score
+                .partial_cmp(&self.items[b].importance_score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        
+        for i in candidates {
+            if total <= cap {
+                break;
+            }
+            let before = self.items[i].token_count;
+            let summary = self.summarize(&self.items[i].content);
+            self.apply_compression(i, summary, outcome);
+            total -= before - self.items[i].token_count;
+        }
+        
+        self.essential_over_cap = total > cap;
+    }
+    
+    /// Remove messages below `removal_importance_threshold` (excluding recent ones)
+    ///
+    /// One front-to-back pass, oldest first. There is deliberately no heap
+    /// index: the eligibility checks and `remove_marked` are linear anyway, so
+    /// a heap only adds upkeep to every insert without changing the bound.
+    fn remove_low_importance_messages(&mut self, outcome: &mut PruneOutcome) {
+        self.telemetry.remove_invocations += 1;
+        let keep_recent = self.config.min_messages;
+        let len = self.items.len();
+        let mut remove = vec![false; len];
+        let mut projected_tokens = self.current_tokens;
+        let target = self.prune_target();
+        
+        for (i, item) in self.items.iter().enumerate().take(len.saturating_sub(keep_recent)) {
+            if projected_tokens <= target {
+                break;
+            }
+            
+            // Remove if low importance and not essential
+            if !self.is_protected(item)
+                && item.importance_score < self.config.removal_importance_threshold
+                && !self.is_essential_message(item)
+            {
+                remove[i] = true;
+                projected_tokens = projected_tokens.saturating_sub(self.item_cost(item));
+            }
+        }
+        
+        self.remove_marked(remove, outcome);
+        self.debug_assert_ordered();
+    }
+    
+    /// Aggressive pruning - keep only the most essential messages
+    ///
+    /// Protected messages and the last `min_messages` always stay; the rest are
+    /// added back in descending importance (plus `aggressive_recency_boost`)
+    /// until the next would exceed the prune target.
+    fn aggressive_prune(&mut self, outcome: &mut PruneOutcome) {
+        self.telemetry.aggressive_invocations += 1;
+        let len = self.items.len();
+        let recent_start = len.saturating_sub(self.config.min_messages);
+        let mut keep: Vec<bool> = self.items.iter()
+            .enumerate()
+            .map(|(i, item)| i >= recent_start || self.is_protected(item))
+            .collect();
+        
+        // Linked messages are kept or dropped together, so budget them as a unit
+        let mut groups: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, item) in self.items.iter().enumerate() {
+            if let Some(group) = item.group_id.as_deref() {
+                groups.entry(group).or_default().push(i);
+            }
+        }
+        for members in groups.values() {
+            if members.iter().any(|&i| keep[i]) {
+                members.iter().for_each(|&i| keep[i] = true);
+            }
+        }
+        
+        let budget = self.prune_target();
+        let mut used: usize = self.items.iter()
+            .zip(&keep)
+            .filter(|(_, &kept)| kept)
+            .map(|(item, _)| self.item_cost(item))
+            .sum();
+        
+        let boost = self.config.aggressive_recency_boost;
+        let rank = |i: usize| self.items[i].importance_score + boost * i as f64 / len as f64;
+        let mut candidates: Vec<usize> = (0..len).filter(|&i| !keep[i]).collect();
+        candidates.sort_by(|&a, &b| {
+            rank(b).partial_cmp(&rank(a)).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        
+        for i in candidates {
+            if keep[i] {
+                continue; // already pulled in with its group
+            }
+            let members = match self.items[i].group_id.as_deref() {
+                Some(group) => groups[group].clone(),
+                None => vec![i],
+            };
+            let cost: usize = members.iter().map(|&j| self.item_cost(&self.items[j])).sum();
+            if used + cost > budget {
+                break;
+            }
+            used += cost;
+            members.into_iter().for_each(|j| keep[j] = true);
+        }
+        
+        let remove: Vec<bool> = keep.iter().map(|&kept| !kept).collect();
+        self.remove_marked(remove, outcome);
+        self.debug_assert_ordered();
+    }
+    
+    /// Enforce `max_messages` by dropping the oldest non-essential messages
+    ///
+    /// Protected, essential and the last `min_messages` are never dropped.
+    fn enforce_max_messages(&mut self, outcome: &mut PruneOutcome) {
+        let Some(max_messages) = self.config.max_messages else { return };
+        let len = self.items.len();
+        if len <= max_messages {
+            return;
+        }
+        
+        let mut excess = len - max_messages;
+        let mut remove = vec![false; len];
+        for (i, item) in self.items.iter().enumerate().take(len.saturating_sub(self.config.min_messages)) {
+            if excess == 0 {
+                break;
+            }
+            if !self.is_protected(item) && !self.is_essential_message(item) {
+                remove[i] = true;
+                excess -= 1;
+            }
+        }
+        
+        self.remove_marked(remove, outcome);
+        self.debug_assert_ordered();
+    }
+    
+    /// Split message indices into (dropped, summarized, full-fidelity) zones, oldest first
+    ///
+    /// The full zone is the newest messages fitting `full_zone_tokens` (or the
+    /// last `full_retention_count`); the summarized zone is the next messages
+    /// fitting `summary_zone_tokens` (or everything older). Messages in the
+    /// dropped zone are removed on the next prune unless protected or essential.
+    pub fn zones(&self) -> (Range<usize>, Range<usize>, Range<usize>) {
+        let full_start = self.full_zone_start();
+        let summary_start = match self.config.summary_zone_tokens {
+            Some(budget) => self.fit_backward(full_start, budget),
+            None => 0,
+        };
+        (0..summary_start, summary_start..full_start, full_start..self.items.len())
+    }
+    
+    /// First index of the full-fidelity zone (never compressed)
+    fn full_zone_start(&self) -> usize {
+        let len = self.items.len();
+        match (self.config.full_zone_tokens, self.config.full_retention_ratio) {
+            (Some(budget), _) => self.fit_backward(len, budget),
+            (None, Some(ratio)) => {
+                let share = (self.current_tokens as f64 * ratio.clamp(0.0, 1.0)) as usize;
+                let mut used = 0;
+                let mut start = len;
+                while start > 0 && used < share {
+                    used += self.item_cost(&self.items[start - 1]);
+                    start -= 1;
+                }
+                start
+            }
+            (None, None) => len.saturating_sub(self.full_retention_count),
+        }
+    }
+    
+    /// Earliest `start` such that the messages in `start..end` cost at most `budget`
+    fn fit_backward(&self, end: usize, budget: usize) -> usize {
+        let mut used = 0;
+        let mut start = end;
+        while start > 0 {
+            let cost = self.item_cost(&self.items[start - 1]);
+            if used + cost > budget {
+                break;
+            }
+            used += cost;
+            start -= 1;
+        }
+        start
+    }
+    
+    /// Remove the dropped zone (see `zones`), sparing protected, essential and
+    /// the last `min_messages` messages
+    fn enforce_zones(&mut self, outcome: &mut PruneOutcome) {
+        let (mut dropped, _, _) = self.zones();
+        dropped.end = dropped.end.min(self.items.len().saturating_sub(self.config.min_messages));
+        if dropped.is_empty() {
+            return;
+        }
+        
+        let remove: Vec<bool> = self.items.iter()
+            .enumerate()
+            .map(|(i, item)| dropped.contains(&i) && !self.is_protected(item) && !self.is_essential_message(item))
+            .collect();
+        self.remove_marked(remove, outcome);
+        self.debug_assert_ordered();
+    }
+    
+    /// Enforce `per_type_budgets`, dropping the lowest-importance items of each
+    /// over-budget type first (protected and recent messages are never touched)
+    fn enforce_type_budgets(&mut self, outcome: &mut PruneOutcome) {
+        if self.config.per_type_budgets.is_empty() {
+            return;
+        }
+        
+        let len = self.items.len();
+        let recent_start = len.saturating_sub(self.config.min_messages);
+        let mut remove = vec![false; len];
+        
+        for (message_type, &budget) in &self.config.per_type_budgets {
+            let mut type_tokens: usize = self.items.iter()
+                .filter(|item| &item.message_type == message_type)
+                .map(|item| self.item_cost(item))
+                .sum();
+            if type_tokens <= budget {
+                continue;
+            }
+            
+            let mut candidates: Vec<usize> = (0..recent_start)
+                .filter(|&i| &self.items[i].message_type == message_type && !self.is_protected(&self.items[i]))
+                .collect();
+            candidates.sort_by(|&a, &b| {
+                self.items[a].importance_score
+                    .partial_cmp(&self.items[b].importance_score)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            
+            for i in candidates {
+                if type_tokens <= budget {
+                    break;
+                }
+                remove[i] = true;
+                type_tokens -= self.item_cost(&self.items[i]);
+            }
+        }
+        
+        self.remove_marked(remove, outcome);
+        self.debug_assert_ordered();
+    }
+    
+    /// Drop every message older than `cutoff`, regardless of importance
+    ///
+    /// Pinned and always-keep-role messages stay, as do older members of a
+    /// group that still has a newer member. Returns the number removed.
+    pub fn remove_before(&mut self, cutoff: Timestamp) -> usize {
+        let remove: Vec<bool> = self.items.iter()
+            .map(|item| item.timestamp < cutoff && !self.is_protected(item))
+            .collect();
+        
+        let mut outcome = PruneOutcome::default();
+        self.remove_marked(remove, &mut outcome);
+        outcome.removed.len()
+    }
+    
+    /// Trim to exactly the newest `count` messages and return the rest, oldest first
+    ///
+    /// A hard trim for archiving: pinning, protected roles and groups are not
+    /// consulted.
+    pub fn keep_last(&mut self, count: usize) -> Vec<ResponseItem> {
+        let excess = self.items.len().saturating_sub(count);
+        let removed: Vec<ResponseItem> = self.items.drain(..excess).collect();
+        for item in &removed {
+            self.release_tokens(self.item_cost(item));
+            self.emit_removed(item);
+        }
+        removed
+    }
+    
+    /// Empty the history for a new conversation, keeping the limits, config,
+    /// tokenizer and hooks
+    ///
+    /// Token totals, telemetry, the headroom reservation and open streams are
+    /// reset; removal hooks do not fire. Stream handles keep counting up, so a
+    /// stale handle never matches a new stream.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.current_tokens = 0;
+        self.total_tokens_ever_added = 0;
+        self.reserved_tokens = 0;
+        self.telemetry = PruneTelemetry::default();
+        self.over_budget_floor = false;
+        self.essential_over_cap = false;
+        self.streams.clear();
+    }
+    
+    /// Break every message over `max_chunk_tokens` into consecutive chunks,
+    /// returning how many messages were split
+    ///
+    /// Chunks keep the role, type, timestamp and metadata, are scored on their
+    /// own content and are pruned independently, so a long message's
+    /// conclusion can outlive its middle. They are linked by `split_of` and
+    /// `split_part` metadata rather than a `group_id`, which would make them
+    /// all-or-nothing. Compressed messages are left alone; no prune runs.
+    pub fn split_large_messages(&mut self, max_chunk_tokens: usize) -> usize {
+        let now = self.now();
+        let mut split = 0;
+        let mut items = VecDeque::with_capacity(self.items.len());
+        
+        for item in std::mem::take(&mut self.items) {
+            if item.token_count <= max_chunk_tokens || item.is_compressed {
+                items.push_back(item);
+                continue;
+            }
+            let chunks = self.chunk_content(&item.content, max_chunk_tokens);
+            if chunks.len() < 2 {
+                items.push_back(item);
+                continue;
+            }
+            
+            split += 1;
+            let split_of = format!("{:016x}", content_hash(&item.content));
+            let parts = chunks.len();
+            for (part, chunk) in chunks.into_iter().enumerate() {
+                let mut piece = item.clone();
+                piece.token_count = self.estimate_tokens(&chunk);
+                piece.content = chunk;
+                piece.metadata.insert("split_of".to_string(), split_of.clone());
+                piece.metadata.insert("split_part".to_string(), format!("{}/{}", part + 1, parts));
+                piece.importance_score = self.calculate_importance(&piece, now);
+                items.push_back(piece);
+            }
+        }
+        
+        self.items = items;
+        self.recompute_tokens();
+        split
+    }
+    
+    /// Cut `content` into pieces of at most `max_tokens`, preferring line breaks,
+    /// then spaces; concatenating the pieces gives back `content`
+    fn chunk_content(&self, content: &str, max_tokens: usize) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut current = String::new();
+        let mut used = 0;
+        
+        for line in content.split_inclusive('\n') {
+            let pieces: Vec<&str> = if self.estimate_tokens(line) > max_tokens {
+                line.split_inclusive(' ').collect()
+            } else {
+                vec![line]
+            };
+            for piece in pieces {
+                let piece_tokens = self.estimate_tokens(piece);
+                if !current.is_empty() && used + piece_tokens > max_tokens {
+                    chunks.push(std::mem::take(&mut current));
+                    used = 0;
+                }
+                current.push_str(piece);
+                used += piece_tokens;
+            }
+        }
+        if !current.is_empty() {
+            chunks.push(current);
+        }
+        chunks
+    }
+    
+    /// Remove duplicate messages (same role and content) per `mode`
+    ///
+    /// `Consecutive` keeps the first of each run (typical of retry logic
+    /// recording one reply twice); `Global` keeps the most recent occurrence.
+    /// Content is compared per `OptimizationConfig::dedup_comparison`; pinned
+    /// messages are never removed. Returns the number of messages removed.
+    pub fn dedup(&mut self, mode: DedupMode) -> usize {
+        let mut remove = vec![false; self.items.len()];
+        
+        match mode {
+            DedupMode::Consecutive => {
+                let mut previous: Option<(&str, DedupKey)> = None;
+                for (i, item) in self.items.iter().enumerate() {
+                    let key = self.dedup_key(&item.content);
+                    if let Some((role, previous_key)) = &previous {
+                        if *role == item.role && *previous_key == key && !item.pinned {
+                            remove[i] = true;
+                            continue;
+                        }
+                    }
+                    previous = Some((&item.role, key));
+                }
+            }
+            DedupMode::Global { window } => {
+                // Walk newest to oldest so the latest occurrence is the one kept
+                let mut seen: HashSet<(&str, DedupKey)> = HashSet::new();
+                for (i, item) in self.items.iter().enumerate().rev().take(window) {
+                    let fresh = seen.insert((&item.role, self.dedup_key(&item.content)));
+                    if !fresh && !item.pinned {
+                        remove[i] = true;
+                    }
+                }
+            }
+        }
+        
+        let mut outcome = PruneOutcome::default();
+        self.remove_marked(remove, &mut outcome);
+        outcome.removed.len()
+    }
+    
+    fn dedup_key<'a>(&self, content: &'a str) -> DedupKey<'a> {
+        match self.config.dedup_comparison {
+            DedupComparison::Exact => DedupKey::Exact(content),
+            DedupComparison::Normalized => {
+                // Case- and whitespace-insensitive fingerprint
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                for word in content.split_whitespace() {
+                    word.to_lowercase().hash(&mut hasher);
+                }
+                DedupKey::Hash(hasher.finish())
+            }
+        }
+    }
+    
+    /// True if timestamps never decrease from oldest to newest
+    pub fn is_chronologically_ordered(&self) -> bool {
+        self.items.iter()
+            .zip(self.items.iter().skip(1))
+            .all(|(earlier, later)| earlier.timestamp <= later.timestamp)
+    }
+    
+    /// Debug-build invariant: pruning must never reorder the transcript
+    fn debug_assert_ordered(&self) {
+        debug_assert!(
+            self.is_chronologically_ordered(),
+            "pruning left messages out of chronological order"
+        );
+    }
+    
+    /// Drop every item whose mask entry is set, preserving chronological order
+    ///
+    /// Linked messages (same `group_id`, e.g. a tool call and its result) are
+    /// atomic: if any member survives, the whole group survives. With
+    /// `preserve_one_per_type`, the most important message of each type that
+    /// would vanish entirely is kept.
+    fn remove_marked(&mut self, mut remove: Vec<bool>, outcome: &mut PruneOutcome) {
+        if !remove.contains(&true) {
+            return;
+        }
+        
+        if self.config.preserve_one_per_type {
+            let mut surviving_types: HashSet<&MessageType> = HashSet::new();
+            let mut best_removed: HashMap<&MessageType, usize> = HashMap::new();
+            for (i, (item, &removed)) in self.items.iter().zip(&remove).enumerate() {
+                if !removed {
+                    surviving_types.insert(&item.message_type);
+                    continue;
+                }
+                let best = best_removed.entry(&item.message_type).or_insert(i);
+                // Newer wins ties
+                if item.importance_score >= self.items[*best].importance_score {
+                    *best = i;
+                }
+            }
+            for (message_type, i) in best_removed {
+                if !surviving_types.contains(message_type) {
+                    remove[i] = false;
+                }
+            }
+        }
+        
+        let kept_groups: HashSet<String> = self.items.iter()
+            .zip(&remove)
+            .filter(|(_, &removed)| !removed)
+            .filter_map(|(item, _)| item.group_id.clone())
+            .collect();
+        if !kept_groups.is_empty() {
+            for (item, removed) in self.items.iter().zip(remove.iter_mut()) {
+                if item.group_id.as_ref().is_some_and(|group| kept_groups.contains(group)) {
+                    *removed = false;
+                }
+            }
+        }
+        
+        outcome.track_removal(self.items.len(), &remove);
+        
+        let mut kept = VecDeque::with_capacity(self.items.len());
+        for (item, removed) in std::mem::take(&mut self.items).into_iter().zip(remove) {
+            if removed {
+                let cost = self.item_cost(&item);
+                self.release_tokens(cost);
+                outcome.tokens_freed += cost;
+                self.emit_removed(&item);
+                outcome.removed.push(item);
+            } else {
+                kept.push_back(item);
+            }
+        }
+        self.items = kept;
+    }
+    
+    /// Calculate importance score for a message
+    fn calculate_importance(&self, item: &ResponseItem, now: Timestamp) -> f64 {
+        self.importance_breakdown(item, now).score
+    }
+    
+    /// Explain the importance score of the message at `index`, factor by factor
+    ///
+    /// Recomputed against the current config and clock, so it can differ from
+    /// the stored `importance_score` if either changed since insertion.
+    pub fn explain_importance(&self, index: usize) -> Option<ImportanceBreakdown> {
+        self.items.get(index).map(|item| self.importance_breakdown(item, self.now()))
+    }
+    
+    /// Every factor of the importance score; `calculate_importance` sums these
+    fn importance_breakdown(&self, item: &ResponseItem, now: Timestamp) -> ImportanceBreakdown {
+        self.importance_breakdown_lowered(item, now, &item.content.to_lowercase())
+    }
+    
+    /// `importance_breakdown` with the content already lowercased by the caller
+    fn importance_breakdown_lowered(&self, item: &ResponseItem, now: Timestamp, content_lower: &str) -> ImportanceBreakdown {
+        let base = 0.5; // Base score
+        
+        // Message type scoring
+        let message_type = match item.message_type {
+            MessageType::ImportantDecision => 0.4,
+            MessageType::ErrorHandling => 0.3,
+            MessageType::UserQuery => 0.2,
+            MessageType::CodeExecution => 0.1,
+            MessageType::SystemResponse => 0.0,
+            MessageType::ContextualInfo => -0.1,
+            MessageType::Custom(ref tag) => self.config.custom_type_weights.get(tag).copied().unwrap_or(0.0),
+        };
+        
+        // Configured keywords (matched against Unicode-aware case-folded content),
+        // minus those the message type already accounts for
+        let implied = self.config.type_implied_keywords.get(&item.message_type);
+        let keyword_hits: Vec<(String, f64)> = self.config.keyword_weights.iter()
+            .filter(|(keyword, _)| !implied.is_some_and(|implied| implied.contains(keyword)))
+            .filter(|(keyword, _)| content_lower.contains(keyword.as_str()))
+            .map(|(keyword, &weight)| (keyword.clone(), weight))
+            .collect();
+        let keywords: f64 = keyword_hits.iter().map(|(_, weight)| weight).sum();
+        
+        // Code presence
+        let has_code = self.config.code_markers.iter()
+            .any(|marker| contains_code_marker(&item.content, marker));
+        let code_presence = if has_code { self.config.code_boost } else { 0.0 };
+        
+        // Length penalty for very long messages (likely verbose); counted in
+        // characters so multi-byte scripts aren't penalized at a third the length
+        let length_penalty = if item.content.chars().count() > 2000 { -0.1 } else { 0.0 };
+        
+        // Recency boost (more recent = slightly higher score)
+        let half_life = self.config.recency_half_life_minutes;
+        let recency = if half_life == LEGACY_RECENCY_CLIFF {
+            let age_minutes = minutes_between(item.timestamp, now);
+            if age_minutes < 60.0 { self.config.recency_weight } else { 0.0 }
+        } else {
+            // Smooth decay: the boost halves every `half_life` minutes
+            let age_minutes = minutes_between(item.timestamp, now).max(0.0);
+            self.config.recency_weight * (-age_minutes * std::f64::consts::LN_2 / half_life).exp()
+        };
+        
+        let raw = base + message_type + keywords + code_presence + length_penalty + recency;
+        let score = match self.config.score_normalization {
+            ScoreNormalization::Clamp => raw.clamp(0.0, 1.0),
+            ScoreNormalization::Sigmoid => 1.0 / (1.0 + (-SIGMOID_STEEPNESS * (raw - 0.5)).exp()),
+        };
+        let score = match self.config.role_importance_floor.get(&item.role) {
+            Some(&floor) => score.max(floor),
+            None => score,
+        };
+        ImportanceBreakdown {
+            base,
+            message_type,
+            keyword_hits,
+            keywords,
+            code_presence,
+            length_penalty,
+            recency,
+            raw_score: raw,
+            score,
+        }
+    }
+    
+    /// Raise the score of every earlier message quoted by the one at `index`
+    ///
+    /// Opt-in via `reference_boost`; a message quoted by several later turns
+    /// is boosted once per turn, capped at 1.0.
+    fn boost_referenced_by(&mut self, index: usize) {
+        let boost = self.config.reference_boost;
+        if boost <= 0.0 {
+            return;
+        }
+        let quotes: Vec<String> = quoted_fragments(&self.items[index].content, self.config.min_reference_chars)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        if quotes.is_empty() {
+            return;
+        }
+        
+        for earlier in self.items.range_mut(..index) {
+            if quotes.iter().any(|quote| earlier.content.contains(quote.as_str())) {
+                earlier.importance_score = (earlier.importance_score + boost).min(1.0);
+            }
+        }
+    }
+    
+    /// Subtract `cost` from `current_tokens`
+    ///
+    /// Debug builds panic on underflow, since it means `current_tokens` has
+    /// drifted below the true item sum; release builds saturate at zero.
+    fn release_tokens(&mut self, cost: usize) {
+        debug_assert!(
+            cost <= self.current_tokens,
+            "token accounting underflow: releasing {} of {} tracked tokens",
+            cost, self.current_tokens
+        );
+        self.current_tokens = self.current_tokens.saturating_sub(cost);
+    }
+    
+    /// Swap an item's `old` cost for `new` in `current_tokens` (see `release_tokens`)
+    fn replace_tokens(&mut self, old: usize, new: usize) {
+        self.release_tokens(old);
+        self.current_tokens += new;
+    }
+    
+    /// Tokens an item contributes to `current_tokens`, including role/framing overhead
+    fn item_cost(&self, item: &ResponseItem) -> usize {
+        item.token_count + self.config.per_message_overhead
+    }
+    
+    /// Check if a message is exempt from eviction (pinned, an always-keep role, or vetoed)
+    fn is_protected(&self, item: &ResponseItem) -> bool {
+        item.pinned
+            || self.config.always_keep_roles.contains(&item.role)
+            || self.is_retained_by_filter(item)
+    }
+    
+    fn is_retained_by_filter(&self, item: &ResponseItem) -> bool {
+        self.hooks.retention_filter.as_ref().is_some_and(|filter| filter(item))
+    }
+    
+    /// Check if a message is essential and should never be removed
+    ///
+    /// Matches `essential_prefixes` and `essential_patterns` case-insensitively.
+    fn is_essential_message(&self, item: &ResponseItem) -> bool {
+        let content_lower = item.content.to_lowercase();
+        
+        self.config.essential_prefixes.iter().any(|prefix| content_lower.starts_with(prefix.as_str()))
+            || self.config.essential_patterns.iter().any(|pattern| content_lower.contains(pattern.as_str()))
+    }
+    
+    /// Summarize content for compression
+    ///
+    /// With `preserve_code_blocks`, only the prose is summarized and every
+    /// fenced code block is carried over verbatim.
+    fn summarize(&self, content: &str) -> String {
+        if !self.config.preserve_code_blocks || !content.contains("```") {
+            return self.summarize_text(content);
+        }
+        
+        let (prose, code_blocks) = split_code_fences(content);
+        with_code_blocks(self.summarize_text(prose.trim()), &code_blocks)
+    }
+    
+    /// Summarize via the caller's hook if set, otherwise the built-in summary
+    fn summarize_text(&self, content: &str) -> String {
+        match &self.hooks.summarizer {
+            Some(summarizer) => summarizer(content),
+            None => self.create_summary(content),
+        }
+    }
+    
+    /// Create a summary of content for compression
+    ///
+    /// Keeps the opening and the conclusion, eliding the middle with `[...]`.
+    fn create_summary(&self, content: &str) -> String {
+        if content.chars().count() <= self.config.compression_min_length {
+            return content.to_string();
+        }
+        
+        let sentences: Vec<&str> = content.split_inclusive(". ").collect();
+        
+        // Head: leading sentences up to summary_head_tokens
+        let head_budget = self.config.summary_head_tokens;
+        let mut head_end = 0;
+        let mut used = 0;
+        for sentence in &sentences {
+            let sentence_tokens = self.estimate_tokens(sentence);
+            if used + sentence_tokens > head_budget {
+                break;
+            }
+            used += sentence_tokens;
+            head_end += 1;
+        }
+        let mut summary = sentences[..head_end].concat().trim_end().to_string();
+        if head_end == 0 && head_budget > 0 {
+            // A single over-long first sentence: keep whole words up to the budget
+            summary = self.truncate_to_tokens(sentences[0], head_budget);
+            head_end = 1;
+        }
+        
+        // Tail: trailing sentences up to summary_tail_tokens, never overlapping the head
+        let mut tail_start = sentences.len();
+        used = 0;
+        while tail_start > head_end {
+            let sentence_tokens = self.estimate_tokens(sentences[tail_start - 1]);
+            if used + sentence_tokens > self.config.summary_tail_tokens {
+                break;
+            }
+            used += sentence_tokens;
+            tail_start -= 1;
+        }
+        if tail_start > head_end {
+            summary.push_str(" [...]");
+        }
+        summary.push(' ');
+        summary.push_str(sentences[tail_start..].concat().trim());
+        
+        let mut summary = summary.trim().to_string();
+        
+        // Carry over must-keep spans the elided sentences contained
+        let mut preserved: Vec<&str> = Vec::new();
+        for pattern in &self.config.summary_preserve_patterns {
+            for span in pattern.find_iter(content).map(|m| m.as_str()) {
+                if !span.is_empty() && !summary.contains(span) && !preserved.contains(&span) {
+                    preserved.push(span);
+                }
+            }
+        }
+        for span in preserved {
+            summary.push(' ');
+            summary.push_str(span);
+        }
+        
+        // Add key technical terms if present
+        if let Some(marker) = &self.config.key_term_marker {
+            let content_lower = content.to_lowercase();
+            let key_terms = ["error", "function", "variable", "config", "solution", "result"];
+            for term in &key_terms {
+                if content_lower.contains(term) && !summary.to_lowercase().contains(term) {
+                    summary.push(' ');
+                    summary.push_str(&marker.replace("{}", term));
+                }
+            }
+        }
+        
+        // Add compressed indicator
+        if let Some(marker) = &self.config.compressed_marker {
+            summary.push(' ');
+            summary.push_str(marker);
+        }
+        
+        summary
+    }
+    
+    /// Cut `item` down to `max_single_message_tokens` (and, under
+    /// `OversizedPolicy::Truncate`, to what fits the limit), marking the cut
+    ///
+    /// Lossy by design: the cut text is not kept. Whitespace is preserved,
+    /// so pasted logs stay readable.
+    fn truncate_oversized(&self, item: &mut ResponseItem) -> bool {
+        let fit = match self.config.oversized_policy {
+            OversizedPolicy::Truncate => Some(self.working_limit().saturating_sub(self.config.per_message_overhead)),
+            OversizedPolicy::Reject | OversizedPolicy::AllowAndWarn => None,
+        };
+        let Some(limit) = self.config.max_single_message_tokens.into_iter().chain(fit).min() else { return false };
+        if item.token_count <= limit {
+            return false;
+        }
+        
+        // Longest character prefix that fits alongside the marker
+        let budget = limit.saturating_sub(self.estimate_tokens(TRUNCATION_MARKER));
+        let prefix = |chars: usize| match item.content.char_indices().nth(chars) {
+            Some((at, _)) => &item.content[..at],
+            None => item.content.as_str(),
+        };
+        let (mut lo, mut hi) = (0, item.content.chars().count());
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            if self.estimate_tokens(prefix(mid)) <= budget {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        
+        item.content = format!("{}{}", prefix(lo), TRUNCATION_MARKER);
+        item.token_count = self.estimate_tokens(&item.content);
+        true
+    }
+    
+    /// Leading whole words of `text` fitting in roughly `max_tokens`, with an ellipsis
+    fn truncate_to_tokens(&self, text: &str, max_tokens: usize) -> String {
+        let mut truncated = String::new();
+        let mut used = 0;
+        
+        for word in text.split_whitespace() {
+            let word_tokens = self.estimate_tokens(word);
+            if used + word_tokens > max_tokens {
+                break;
+            }
+            if !truncated.is_empty() {
+                truncated.push(' ');
+            }
+            truncated.push_str(word);
+            used += word_tokens;
+        }
+        
+        truncated.push('…');
+        truncated
+    }
+    
+    /// Estimate token count for text using the configured tokenizer
+    fn estimate_tokens(&self, text: &str) -> usize {
+        self.tokenizer.count(text)
+    }
+    
+    /// Snapshot the conversation for a later `rollback`
+    ///
+    /// A full clone of every message: O(n) time and memory per checkpoint.
+    pub fn checkpoint(&self) -> HistoryCheckpoint {
+        HistoryCheckpoint {
+            items: self.items.clone(),
+            total_tokens_ever_added: self.total_tokens_ever_added,
+            over_budget_floor: self.over_budget_floor,
+        }
+    }
+    
+    /// Return the conversation to `checkpoint`, discarding everything since
+    ///
+    /// Only messages and their bookkeeping roll back; config, hooks, reservations
+    /// and in-progress streams are left as they are.
+    pub fn rollback(&mut self, checkpoint: HistoryCheckpoint) {
+        self.items = checkpoint.items;
+        self.total_tokens_ever_added = checkpoint.total_tokens_ever_added;
+        self.over_budget_floor = checkpoint.over_budget_floor;
+        
+        // Re-sum rather than trusting a stale total (the overhead may have changed)
+        self.recompute_tokens();
+    }
+    
+    /// Hold back `tokens` of headroom for an upcoming completion
+    ///
+    /// Pruning targets `max_tokens - tokens` until `clear_reservation`;
+    /// prunes immediately to make the room.
+    pub fn reserve_headroom(&mut self, tokens: usize) -> PruneOutcome {
+        self.reserved_tokens = tokens;
+        self.intelligent_prune()
+    }
+    
+    /// Drop any headroom reservation, restoring the full `max_tokens` limit
+    pub fn clear_reservation(&mut self) {
+        self.reserved_tokens = 0;
+    }
+    
+    /// Proactive compression threshold: `soft_limit_ratio` of the working limit
+    fn soft_limit(&self) -> usize {
+        (self.working_limit() as f64 * self.config.soft_limit_ratio.clamp(0.0, 1.0)) as usize
+    }
+    
+    /// Where removal stops once triggered: `prune_target_ratio` of the working limit
+    fn prune_target(&self) -> usize {
+        (self.working_limit() as f64 * self.config.prune_target_ratio.clamp(0.0, 1.0)) as usize
+    }
+    
+    /// Effective pruning limit: `max_tokens` minus any reserved headroom
+    fn working_limit(&self) -> usize {
+        self.max_tokens.saturating_sub(self.reserved_tokens)
+    }
+    
+    /// Current token count (O(1))
+    pub fn current_tokens(&self) -> usize {
+        self.current_tokens
+    }
+    
+    /// Configured token limit (O(1))
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+    
+    /// Tokens that can still be added before pruning kicks in (O(1))
+    ///
+    /// Measured against the soft limit, where compression starts, so it
+    /// accounts for `soft_limit_ratio` and reserved headroom; 0 when already
+    /// past it.
+    pub fn free_space(&self) -> usize {
+        self.soft_limit().saturating_sub(self.current_tokens)
+    }
+    
+    /// Whether a message of `tokens` content tokens would fit without triggering a prune
+    ///
+    /// Includes `per_message_overhead`, matching what `add_message` would charge.
+    pub fn can_fit(&self, tokens: usize) -> bool {
+        tokens + self.config.per_message_overhead <= self.free_space()
+    }
+    
+    /// Fraction of `max_tokens` in use, e.g. 0.93 (O(1), may exceed 1.0)
+    ///
+    /// A zero `max_tokens` reports 0.0 rather than NaN or infinity.
+    pub fn utilization(&self) -> f64 {
+        if self.max_tokens == 0 {
+            return 0.0;
+        }
+        self.current_tokens as f64 / self.max_tokens as f64
+    }
+    
+    /// Every token ever added, before any pruning or compression (O(1))
+    pub fn total_tokens_ever_added(&self) -> usize {
+        self.total_tokens_ever_added
+    }
+    
+    /// Fraction of all added tokens pruned away: `1 - current / ever_added`
+    ///
+    /// 0.2 means the history is 20% smaller than an unpruned one would be.
+    pub fn reduction_ratio(&self) -> f64 {
+        if self.total_tokens_ever_added == 0 {
+            return 0.0;
+        }
+        (1.0 - self.current_tokens as f64 / self.total_tokens_ever_added as f64).max(0.0)
+    }
+    
+    /// Cumulative strategy counters since creation or the last `reset_telemetry`
+    ///
+    /// A constantly climbing `aggressive_invocations` means `max_tokens` is
+    /// too low for the workload.
+    pub fn telemetry(&self) -> PruneTelemetry {
+        self.telemetry
+    }
+    
+    pub fn reset_telemetry(&mut self) {
+        self.telemetry = PruneTelemetry::default();
+    }
+    
+    /// Timestamp of the oldest retained message (O(1): items are chronological)
+    pub fn oldest_timestamp(&self) -> Option<Timestamp> {
+        self.items.front().map(|item| item.timestamp)
+    }
+    
+    /// Timestamp of the newest retained message (O(1))
+    pub fn newest_timestamp(&self) -> Option<Timestamp> {
+        self.items.back().map(|item| item.timestamp)
+    }
+    
+    /// Time covered by the retained messages, newest minus oldest (O(1))
+    pub fn span(&self) -> Option<TimeSpan> {
+        Some(span_between(self.oldest_timestamp()?, self.newest_timestamp()?))
+    }
+    
+    /// Re-sum every item's tokens, reset `current_tokens`, and return the correction
+    ///
+    /// A non-zero result (true total minus the tracked total) means the
+    /// incremental bookkeeping drifted.
+    pub fn recompute_tokens(&mut self) -> i64 {
+        let actual: usize = self.items.iter().map(|item| self.item_cost(item)).sum();
+        let delta = actual as i64 - self.current_tokens as i64;
+        self.current_tokens = actual;
+        delta
+    }
+    
+    /// Get current token usage statistics
+    pub fn get_stats(&self) -> ConversationStats {
+        ConversationStats {
+            total_messages: self.items.len(),
+            total_tokens: self.current_tokens,
+            max_tokens: self.max_tokens,
+            utilization_percentage: self.utilization_pct(),
+            utilization_exact: self.utilization() * 100.0,
+            compressed_messages: self.items.iter()
+                .filter(|item| item.is_compressed)
+                .count(),
+            high_importance_messages: self.items.iter()
+                .filter(|item| item.importance_score > 0.7)
+                .count(),
+            over_budget_floor: self.over_budget_floor,
+            essential_over_cap: self.essential_over_cap,
+            reduction_ratio: self.reduction_ratio(),
+            oldest: self.oldest_timestamp(),
+            newest: self.newest_timestamp(),
+            token_breakdown: None,
+        }
+    }
+    
+    /// `get_stats` plus the per-type `token_breakdown`
+    pub fn get_stats_with_breakdown(&self) -> ConversationStats {
+        ConversationStats {
+            token_breakdown: Some(self.token_breakdown()),
+            ..self.get_stats()
+        }
+    }
+    
+    /// Tokens per message type, including per-message overhead (sums to `current_tokens`)
+    pub fn token_breakdown(&self) -> HashMap<MessageType, usize> {
+        let mut breakdown = HashMap::new();
+        for item in &self.items {
+            *breakdown.entry(item.message_type.clone()).or_insert(0) += self.item_cost(item);
+        }
+        breakdown
+    }
+    
+    /// Export conversation for analysis
+    pub fn export_for_analysis(&self) -> Vec<&ResponseItem> {
+        self.items.iter().collect()
+    }
+    
+    /// Iterate messages in chronological order without allocating
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ResponseItem> + ExactSizeIterator {
+        self.items.iter()
+    }
+    
+    /// Iterate only compressed (or coalesced) messages, in chronological order
+    pub fn compressed_messages(&self) -> impl Iterator<Item = &ResponseItem> {
+        self.items.iter().filter(|item| item.is_compressed)
+    }
+    
+    /// Pre-compression text of the message at `index`, if it was compressed
+    pub fn original_of(&self, index: usize) -> Option<&str> {
+        self.items.get(index)?.original_content.as_deref()
+    }
+    
+    /// Edit messages in place through a mutable iterator, then fix up token counts
+    ///
+    /// Scoped to a closure so bookkeeping always runs afterward: messages whose
+    /// content changed are re-counted and `current_tokens` is re-summed. Scores
+    /// are not touched (see `rescore_all`), and no prune runs.
+    pub fn with_iter_mut<R>(&mut self, f: impl FnOnce(std::collections::vec_deque::IterMut<'_, ResponseItem>) -> R) -> R {
+        let before: Vec<u64> = self.items.iter().map(|item| content_hash(&item.content)).collect();
+        let result = f(self.items.iter_mut());
+        
+        let tokenizer = Arc::clone(&self.tokenizer);
+        for (item, hash) in self.items.iter_mut().zip(before) {
+            if item.token_count == 0 || content_hash(&item.content) != hash {
+                item.token_count = tokenizer.count(&item.content);
+            }
+        }
+        self.recompute_tokens();
+        result
+    }
+    
+    /// Find all messages matching `predicate`, in chronological order
+    pub fn find(&self, predicate: impl Fn(&ResponseItem) -> bool) -> Vec<&ResponseItem> {
+        self.items.iter().filter(|item| predicate(item)).collect()
+    }
+    
+    /// Find all messages of the given type, in chronological order
+    pub fn find_by_type(&self, message_type: MessageType) -> Vec<&ResponseItem> {
+        self.find(|item| item.message_type == message_type)
+    }
+    
+    /// Find all messages whose content contains `substring`
+    pub fn search_content(&self, substring: &str, case_insensitive: bool) -> Vec<&ResponseItem> {
+        if case_insensitive {
+            let needle = substring.to_lowercase();
+            self.find(|item| item.content.to_lowercase().contains(&needle))
+        } else {
+            self.find(|item| item.content.contains(substring))
+        }
+    }
+    
+    /// Write one JSON `ResponseItem` per line (JSON Lines), oldest first
+    ///
+    /// Streams message by message, so long histories never build one big
+    /// array in memory. Each line carries scores and metadata.
+    pub fn export_jsonl<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for item in &self.items {
+            serde_json::to_writer(&mut *writer, item)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+    
+    /// Save the history as a versioned JSON envelope
+    ///
+    /// Writes to a temporary sibling file first, syncs it and renames it into
+    /// place, so a crash mid-write never leaves a truncated history behind.
+    /// The tokenizer is not persisted.
+    #[cfg(feature = "fs")]
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, |writer| {
+            let envelope = HistoryEnvelopeRef {
+                format_version: HISTORY_FORMAT_VERSION,
+                history: self,
+            };
+            Ok(serde_json::to_writer(writer, &envelope)?)
+        })
+    }
+    
+    /// Save the history in compact binary form (MessagePack) for internal persistence
+    ///
+    /// Much faster and smaller than `save_to_path` for large archives, but not
+    /// human-readable. Uses a self-describing format because optional fields
+    /// are omitted when empty, which bincode and postcard cannot round-trip.
+    #[cfg(feature = "binary")]
+    pub fn save_binary(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, |writer| {
+            rmp_serde::encode::write(writer, &HISTORY_FORMAT_VERSION).map_err(io::Error::other)?;
+            rmp_serde::encode::write_named(writer, self).map_err(io::Error::other)
+        })
+    }
+    
+    /// Load a history written by `save_binary`; same version check and
+    /// tokenizer reset as `load_from_path`
+    #[cfg(feature = "binary")]
+    pub fn load_binary(path: &Path) -> io::Result<Self> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let version: u32 = rmp_serde::from_read(&mut reader).map_err(invalid)?;
+        if version != HISTORY_FORMAT_VERSION {
+            return Err(invalid(rmp_serde::decode::Error::Uncategorized(format!(
+                "unsupported history format version {} (expected <= {})",
+                version, HISTORY_FORMAT_VERSION
+            ))));
+        }
+        
+        let mut history: Self = rmp_serde::from_read(reader).map_err(invalid)?;
+        history.recount_all();
+        Ok(history)
+    }
+    
+    /// Load a history written by `save_to_path`
+    ///
+    /// Fails with `InvalidData` for unknown format versions. The tokenizer is
+    /// reset to the heuristic default and stored counts are re-taken with it;
+    /// call `set_tokenizer` to change it.
+    #[cfg(feature = "fs")]
+    pub fn load_from_path(path: &Path) -> io::Result<Self> {
+        Self::load_from_path_with(path, LoadOptions::default())
+    }
+    
+    /// Load a history, optionally swapping in a new config and re-scoring
+    #[cfg(feature = "fs")]
+    pub fn load_from_path_with(path: &Path, options: LoadOptions) -> io::Result<Self> {
+        let mut history = Self::read_envelope(path)?;
+        
+        if let Some(config) = options.config {
+            history.apply_config(config);
+        }
+        // After the config, whose `per_message_overhead` feeds every item's cost
+        history.recount_all();
+        if options.rescore {
+            history.rescore_all();
+        }
+        
+        Ok(history)
+    }
+    
+    #[cfg(feature = "fs")]
+    fn read_envelope(path: &Path) -> io::Result<Self> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        let envelope: HistoryEnvelope = serde_json::from_reader(reader)?;
+        
+        match envelope.format_version {
+            1 => Ok(serde_json::from_value(envelope.history)?),
+            version => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported history format version {} (expected <= {})",
+                    version, HISTORY_FORMAT_VERSION
+                ),
+            )),
+        }
+    }
+}
+
+/// Write through `write` to a temporary sibling of `path`, sync it and rename
+/// it into place, so a crash mid-write never leaves a truncated file behind
+#[cfg(feature = "fs")]
+fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<fs::File>) -> io::Result<()>) -> io::Result<()> {
+    let tmp_path = temp_sibling(path);
+    let result = fs::File::create(&tmp_path)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp_path, path));
+    
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Thread-safe handle for several workers appending to one conversation
+///
+/// Cloning the handle shares the same history. A worker that panics while
+/// holding the lock does not poison the handle for everyone else; the
+/// history is used as-is.
+#[derive(Debug, Clone)]
+pub struct SharedHistory {
+    inner: Arc<RwLock<OptimizedConversationHistory>>,
+}
+
+impl SharedHistory {
+    pub fn new(history: OptimizedConversationHistory) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(history)),
+        }
+    }
+    
+    /// Add a message under the write lock
+    pub fn add_message(&self, item: ResponseItem) -> PruneOutcome {
+        self.write().add_message_reporting(item)
+    }
+    
+    /// Snapshot statistics under a read lock
+    pub fn get_stats(&self) -> ConversationStats {
+        self.read().get_stats()
+    }
+    
+    /// Owned copy of every retained message
+    pub fn export_for_analysis(&self) -> Vec<ResponseItem> {
+        self.read().export_for_analysis().into_iter().cloned().collect()
+    }
+    
+    /// Run `f` with shared access to the history
+    pub fn with_read<R>(&self, f: impl FnOnce(&OptimizedConversationHistory) -> R) -> R {
+        f(&self.read())
+    }
+    
+    /// Run `f` with exclusive access to the history
+    pub fn with_write<R>(&self, f: impl FnOnce(&mut OptimizedConversationHistory) -> R) -> R {
+        f(&mut self.write())
+    }
+    
+    fn read(&self) -> RwLockReadGuard<'_, OptimizedConversationHistory> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    
+    fn write(&self) -> RwLockWriteGuard<'_, OptimizedConversationHistory> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl From<OptimizedConversationHistory> for SharedHistory {
+    fn from(history: OptimizedConversationHistory) -> Self {
+        Self::new(history)
+    }
+}
+
+/// A scratch file next to `path`, unique per process and call, so concurrent
+/// saves never share (and clobber) one temporary file
+#[cfg(feature = "fs")]
+fn temp_sibling(path: &Path) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+    
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.{}.tmp", std::process::id(), NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)));
+    path.with_file_name(name)
+}
+
+/// Interface shared by the optimized history and the `PassthroughHistory`
+/// baseline, so a workload can be A/B tested against both
+pub trait History {
+    fn add_message(&mut self, item: ResponseItem);
+    fn get_stats(&self) -> ConversationStats;
+    fn items(&self) -> Vec<&ResponseItem>;
+}
+
+impl History for OptimizedConversationHistory {
+    fn add_message(&mut self, item: ResponseItem) {
+        OptimizedConversationHistory::add_message(self, item)
+    }
+    
+    fn get_stats(&self) -> ConversationStats {
+        OptimizedConversationHistory::get_stats(self)
+    }
+    
+    fn items(&self) -> Vec<&ResponseItem> {
+        self.export_for_analysis()
+    }
+}
+
+/// Unoptimized baseline: keeps every message verbatim and never prunes
+///
+/// Counts tokens like `OptimizedConversationHistory` (tokenizer plus
+/// `per_message_overhead`), so stats compare like for like.
+#[derive(Debug, Clone)]
+pub struct PassthroughHistory {
+    items: Vec<ResponseItem>,
+    max_tokens: usize,
+    current_tokens: usize,
+    per_message_overhead: usize,
+    tokenizer: Arc<dyn Tokenizer>,
+}
+
+impl PassthroughHistory {
+    pub fn new(max_tokens: usize) -> Self {
+        Self::with_tokenizer(max_tokens, HeuristicTokenizer)
+    }
+    
+    /// Baseline counting tokens with the same tokenizer as the history under test
+    pub fn with_tokenizer(max_tokens: usize, tokenizer: impl Tokenizer + 'static) -> Self {
+        Self {
+            items: Vec::new(),
+            max_tokens,
+            current_tokens: 0,
+            per_message_overhead: OptimizationConfig::default().per_message_overhead,
+            tokenizer: Arc::new(tokenizer),
+        }
+    }
+}
+
+impl History for PassthroughHistory {
+    fn add_message(&mut self, mut item: ResponseItem) {
+        if item.token_count == 0 {
+            item.token_count = self.tokenizer.count(&item.content);
+        }
+        self.current_tokens += item.token_count + self.per_message_overhead;
+        self.items.push(item);
+    }
+    
+    /// Importance is never scored, so `high_importance_messages` is always 0;
+    /// `over_budget_floor` reports whether the baseline exceeds `max_tokens`
+    fn get_stats(&self) -> ConversationStats {
+        let utilization = match self.max_tokens {
+            0 => 0.0,
+            max_tokens => self.current_tokens as f64 / max_tokens as f64,
+        };
+        ConversationStats {
+            total_messages: self.items.len(),
+            total_tokens: self.current_tokens,
+            max_tokens: self.max_tokens,
+            utilization_percentage: (utilization * 100.0) as u32,
+            utilization_exact: utilization * 100.0,
+            compressed_messages: 0,
+            high_importance_messages: 0,
+            over_budget_floor: self.current_tokens > self.max_tokens,
+            essential_over_cap: false,
+            reduction_ratio: 0.0,
+            oldest: self.items.first().map(|item| item.timestamp),
+            newest: self.items.last().map(|item| item.timestamp),
+            token_breakdown: None,
+        }
+    }
+    
+    fn items(&self) -> Vec<&ResponseItem> {
+        self.items.iter().collect()
+    }
+}
+
+/// Saved conversation state from `checkpoint`, consumed by `rollback`
+#[derive(Debug, Clone)]
+pub struct HistoryCheckpoint {
+    items: VecDeque<ResponseItem>,
+    total_tokens_ever_added: usize,
+    over_budget_floor: bool,
+}
+
+/// Identifies an in-progress message started with `begin_streaming`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamHandle(u64);
+
+/// Builder for `OptimizedConversationHistory` retention settings
+#[derive(Debug)]
+pub struct OptimizedConversationHistoryBuilder {
+    history: OptimizedConversationHistory,
+}
+
+impl OptimizedConversationHistoryBuilder {
+    /// Maximum token limit for the entire history
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.history.max_tokens = max_tokens;
+        self.history.config.max_tokens = max_tokens;
+        self
+    }
+    
+    /// Minimum recent messages to always keep (e.g. 3 for 8K windows, 50 for 200K)
+    pub fn min_messages(mut self, min_messages: usize) -> Self {
+        self.history.config.min_messages = min_messages;
+        self
+    }
+    
+    /// Recent messages kept in full detail (never compressed)
+    pub fn full_retention_count(mut self, full_retention_count: usize) -> Self {
+        self.history.full_retention_count = full_retention_count;
+        self
+    }
+    
+    /// Token counter to use instead of the heuristic default
+    pub fn tokenizer(mut self, tokenizer: impl Tokenizer + 'static) -> Self {
+        self.history.tokenizer = Arc::new(tokenizer);
+        self
+    }
+    
+    pub fn build(self) -> OptimizedConversationHistory {
+        self.history
+    }
+}
+
+/// Builder for `ResponseItem`, started with `ResponseItem::builder`
+#[derive(Debug, Clone)]
+pub struct ResponseItemBuilder {
+    item: ResponseItem,
+}
+
+impl ResponseItemBuilder {
+    pub fn message_type(mut self, message_type: MessageType) -> Self {
+        self.item.message_type = message_type;
+        self
+    }
+    
+    /// Override the creation time (defaults to now)
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.item.timestamp = timestamp;
+        self
+    }
+    
+    /// Never compress or remove this message
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.item.pinned = pinned;
+        self
+    }
+    
+    /// Link to other messages that must be kept or dropped together
+    pub fn group_id(mut self, group_id: impl Into<String>) -> Self {
+        self.item.group_id = Some(group_id.into());
+        self
+    }
+    
+    /// Attach a caller-defined annotation
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.item.metadata.insert(key.into(), value.into());
+        self
+    }
+    
+    pub fn build(self) -> ResponseItem {
+        self.item
+    }
+}
+
+/// Per-factor contributions to a message's importance score
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportanceBreakdown {
+    pub base: f64,
+    
+    /// Delta from the message type
+    pub message_type: f64,
+    
+    /// Each matched keyword and its weight
+    pub keyword_hits: Vec<(String, f64)>,
+    
+    /// Sum of `keyword_hits`
+    pub keywords: f64,
+    
+    pub code_presence: f64,
+    pub length_penalty: f64,
+    pub recency: f64,
+    
+    /// Sum of every factor, before normalization (may fall outside [0, 1])
+    pub raw_score: f64,
+    
+    /// Final score after `score_normalization` and any `role_importance_floor`
+    pub score: f64,
+}
+
+/// Appended to messages cut down to `max_single_message_tokens`
+pub const TRUNCATION_MARKER: &str = "\n… [truncated]";
+
+/// Smallest `max_tokens` accepted by `try_new`
+pub const MIN_MAX_TOKENS: usize = 100;
+
+/// Why a history could not be constructed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    MaxTokensTooSmall { max_tokens: usize, min: usize },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxTokensTooSmall { max_tokens, min } => write!(
+                f,
+                "max_tokens {} is too small (minimum {})",
+                max_tokens, min
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A message index past the end of the history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexError {
+    pub index: usize,
+    pub len: usize,
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no message at index {} (history has {})", self.index, self.len)
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+/// A message refused by `OversizedPolicy::Reject`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OversizedError {
+    /// Cost of the message, including per-message overhead
+    pub tokens: usize,
+    pub max_tokens: usize,
+}
+
+impl fmt::Display for OversizedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message of {} tokens exceeds the {} token limit on its own", self.tokens, self.max_tokens)
+    }
+}
+
+impl std::error::Error for OversizedError {}
+
+/// Why a compressed message could not be restored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    IndexOutOfRange(usize),
+    NotCompressed(usize),
+    OverBudget { needed: usize, max_tokens: usize },
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IndexOutOfRange(index) => write!(f, "no message at index {}", index),
+            Self::NotCompressed(index) => write!(f, "message {} has no original content to restore", index),
+            Self::OverBudget { needed, max_tokens } => write!(
+                f,
+                "restoring would need {} tokens, over the {} token limit",
+                needed, max_tokens
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// What a pruning pass removed or compressed
+#[derive(Debug, Clone, Default)]
+pub struct PruneOutcome {
+    /// Messages dropped from the history, in removal order
+    pub removed: Vec<ResponseItem>,
+    
+    /// Compressed messages as (index at the start of the pass, original content)
+    pub compressed: Vec<(usize, String)>,
+    
+    /// Total tokens reclaimed by this pass
+    pub tokens_freed: usize,
+    
+    /// Messages cut down to `max_single_message_tokens` on insert
+    pub truncated: usize,
+    
+    /// Messages dropped on insert by `OversizedPolicy::Reject`
+    pub rejected: usize,
+    
+    /// Start-of-pass index of each surviving item (empty until something is removed)
+    origin: Vec<usize>,
+}
+
+impl PruneOutcome {
+    /// True if pruning did not touch the history
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.compressed.is_empty() && self.truncated == 0 && self.rejected == 0
+    }
+    
+    /// Map a current index back to its index at the start of the pass
+    fn origin_of(&self, index: usize) -> usize {
+        self.origin.get(index).copied().unwrap_or(index)
+    }
+    
+    /// Record a removal mask over `len` current items
+    fn track_removal(&mut self, len: usize, remove: &[bool]) {
+        if self.origin.is_empty() {
+            self.origin = (0..len).collect();
+        }
+        let origin = std::mem::take(&mut self.origin);
+        self.origin = origin.into_iter()
+            .zip(remove)
+            .filter(|(_, &removed)| !removed)
+            .map(|(index, _)| index)
+            .collect();
+    }
+}
+
+/// How many times each pruning strategy has run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PruneTelemetry {
+    /// Compression passes (one-by-one or coalescing)
+    pub compress_invocations: u64,
+    
+    /// Low-importance removal passes
+    pub remove_invocations: u64,
+    
+    /// Aggressive pruning passes
+    pub aggressive_invocations: u64,
+}
+
+/// Index-level view of a pruning pass: previewed by `simulate_prune`, or run by `trim_to`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Indices (at the start of the pass) removed
+    pub removed_indices: Vec<usize>,
+    
+    /// Indices (at the start of the pass) compressed and surviving
+    pub compressed_indices: Vec<usize>,
+    
+    /// `current_tokens` after the pass
+    pub projected_tokens: usize,
+}
+
+impl PruneReport {
+    fn from_outcome(outcome: &PruneOutcome, len_before: usize, projected_tokens: usize) -> Self {
+        let mut survives = vec![outcome.origin.is_empty(); len_before];
+        for &index in &outcome.origin {
+            survives[index] = true;
+        }
+        
+        Self {
+            removed_indices: (0..len_before).filter(|&i| !survives[i]).collect(),
+            compressed_indices: outcome.compressed.iter()
+                .map(|&(index, _)| index)
+                .filter(|&index| survives[index])
+                .collect(),
+            projected_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationStats {
+    pub total_messages: usize,
+    pub total_tokens: usize,
+    pub max_tokens: usize,
+    
+    /// Whole percent, truncated: 99.6% reports as 99
+    pub utilization_percentage: u32,
+    
+    /// Unrounded percentage, e.g. 99.6 (may exceed 100.0); use this for alerting
+    pub utilization_exact: f64,
+    
+    pub compressed_messages: usize,
+    pub high_importance_messages: usize,
+    
+    /// True if the last prune could not get under `max_tokens`
+    pub over_budget_floor: bool,
+    
+    /// True if essential messages still exceed `essential_token_ratio` after compressing them
+    pub essential_over_cap: bool,
+    
+    /// Fraction of all added tokens removed by pruning and compression
+    pub reduction_ratio: f64,
+    
+    /// Time range covered by the retained messages (`None` when empty)
+    pub oldest: Option<Timestamp>,
+    pub newest: Option<Timestamp>,
+    
+    /// Tokens per message type (only from `get_stats_with_breakdown`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_breakdown: Option<HashMap<MessageType, usize>>,
+}
+
+// 🧪 TDD hooks for the core optimizer
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn item(content: &str, role: &str, message_type: MessageType) -> ResponseItem {
+        ResponseItem::builder(content, role).message_type(message_type).build()
+    }
+    
+    #[test]
+    fn test_pinned_message_survives_pruning() {
+        let mut history = OptimizedConversationHistory::new(2_000);
+        let schema = format!("Tool schema definition: {}", "x".repeat(400));
+        history.add_message(item(&schema, "developer", MessageType::ContextualInfo));
+        assert!(history.pin_message(0));
+        
+        for i in 0..200 {
+            let filler = format!("Filler chatter {} {}", i, "y".repeat(300));
+            history.add_message(item(&filler, "assistant", MessageType::ContextualInfo));
+        }
+        
+        let items = history.export_for_analysis();
+        assert_eq!(items[0].content, schema, "pinned message was removed or compressed");
+        assert!(items[0].pinned);
+    }
+    
+    #[test]
+    fn test_aggressive_prune_keeps_tool_groups_intact() {
+        let mut history = OptimizedConversationHistory::builder()
+            .max_tokens(20)
+            .min_messages(2)
+            .build();
+        
+        let mut call = item("call get_weather", "assistant", MessageType::ContextualInfo);
+        call.group_id = Some("call-1".to_string());
+        let mut result = item("critical error: timeout", "tool", MessageType::ErrorHandling);
+        result.group_id = Some("call-1".to_string());
+        
+        history.add_message(call);
+        history.add_message(result);
+        history.add_message(item("thanks, continue", "user", MessageType::UserQuery));
+        
+        let items = history.export_for_analysis();
+        assert!(items.iter().any(|item| item.content == "call get_weather"),
+                "tool call dropped while its result was kept");
+        assert!(items.iter().any(|item| item.content == "critical error: timeout"));
+    }
+    
+    #[test]
+    fn test_retention_filter_vetoes_eviction() {
+        let mut history = OptimizedConversationHistory::builder()
+            .max_tokens(60)
+            .min_messages(1)
+            .build();
+        history.set_retention_filter(|item| item.metadata.get("task").is_some_and(|task| task == "T-42"));
+        
+        let mut tracked = item("status update", "assistant", MessageType::ContextualInfo);
+        tracked.metadata.insert("task".to_string(), "T-42".to_string());
+        history.add_message(tracked);
+        for i in 0..50 {
+            history.add_message(item(&format!("filler {}", i), "assistant", MessageType::ContextualInfo));
+        }
+        
+        assert!(history.export_for_analysis().iter().any(|item| item.content == "status update"));
+    }
+    
+    #[test]
+    fn test_length_penalty_counts_characters_not_bytes() {
+        let history = OptimizedConversationHistory::new(100_000);
+        
+        // 700 characters, 2100 bytes in UTF-8
+        let japanese = item(&"あ".repeat(700), "user", MessageType::UserQuery);
+        let now = now_timestamp();
+        assert_eq!(history.importance_breakdown(&japanese, now).length_penalty, 0.0);
+        
+        let long = item(&"a".repeat(2001), "user", MessageType::UserQuery);
+        assert_eq!(history.importance_breakdown(&long, now).length_penalty, -0.1);
+    }
+    
+    #[test]
+    fn test_rollback_restores_messages_and_tokens() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("plan the refactor", "user", MessageType::UserQuery));
+        
+        let checkpoint = history.checkpoint();
+        let tokens = history.current_tokens();
+        history.add_message(item("speculative branch", "assistant", MessageType::SystemResponse));
+        history.add_message(item("dead end", "assistant", MessageType::SystemResponse));
+        
+        history.rollback(checkpoint);
+        assert_eq!(history.export_for_analysis().len(), 1);
+        assert_eq!(history.current_tokens(), tokens);
+        assert_eq!(history.recompute_tokens(), 0);
+    }
+    
+    #[test]
+    fn test_with_iter_mut_recounts_edited_messages() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("short", "user", MessageType::UserQuery));
+        history.add_message(item("also short", "assistant", MessageType::SystemResponse));
+        let before = history.current_tokens();
+        
+        history.with_iter_mut(|mut items| {
+            if let Some(first) = items.next() {
+                first.content = "a much longer replacement message than before".to_string();
+            }
+        });
+        
+        assert!(history.current_tokens() > before);
+        assert_eq!(history.recompute_tokens(), 0);
+        assert_eq!(history.iter().count(), 2);
+    }
+    
+    #[test]
+    fn test_coalesce_merges_runs_and_saves_overhead() {
+        let mut history = OptimizedConversationHistory::builder()
+            .full_retention_count(2)
+            .build();
+        for i in 0..6 {
+            history.add_message(item(&format!("chatter {}", i), "assistant", MessageType::ContextualInfo));
+        }
+        let before = history.current_tokens();
+        
+        let outcome = history.coalesce_old_messages();
+        
+        assert_eq!(history.iter().count(), 3, "four old messages should merge into one");
+        assert_eq!(outcome.removed.len(), 3);
+        assert_eq!(before - history.current_tokens(), outcome.tokens_freed);
+        assert_eq!(history.recompute_tokens(), 0);
+        assert!(history.iter().next().is_some_and(|first| first.content.contains("chatter 3")));
+    }
+    
+    #[test]
+    fn test_sigmoid_keeps_saturated_scores_ordered() {
+        let config = OptimizationConfig {
+            score_normalization: ScoreNormalization::Sigmoid,
+            recency_weight: 0.0,
+            ..OptimizationConfig::default()
+        };
+        let history = OptimizedConversationHistory::with_config(config);
+        let now = now_timestamp();
+        
+        let decision = item("important fix", "user", MessageType::ImportantDecision);
+        let critical = item("important critical fix for the bug", "user", MessageType::ImportantDecision);
+        let high = history.importance_breakdown(&decision, now);
+        let higher = history.importance_breakdown(&critical, now);
+        
+        assert!(high.raw_score > 1.0 && higher.raw_score > high.raw_score);
+        assert!(higher.score > high.score && higher.score < 1.0);
     }
     
-    /// Add new message with automatic pruning
-    pub fn add_message(&mut self, mut item: ResponseItem) {
-        // Calculate token count if not provided
-        if item.token_count == 0 {
-            item.token_count = self.estimate_tokens(&item.content);
+    #[test]
+    fn test_zones_follow_token_budgets() {
+        let config = OptimizationConfig {
+            min_messages: 2,
+            full_zone_tokens: Some(30),
+            summary_zone_tokens: Some(30),
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        // Each message costs 10 tokens: 6 content + 4 overhead
+        for i in 0..4 {
+            let mut message = item(&format!("turn {}", i), "assistant", MessageType::ContextualInfo);
+            message.token_count = 6;
+            history.insert_item(message).unwrap();
         }
+        assert_eq!(history.zones(), (0..0, 0..1, 1..4));
         
-        // Calculate importance score
-        item.importance_score = self.calculate_importance(&item);
+        for i in 4..8 {
+            let mut message = item(&format!("turn {}", i), "assistant", MessageType::ContextualInfo);
+            message.token_count = 6;
+            history.add_message(message);
+        }
+        
+        // The dropped zone was pruned away; what remains is summarized + full
+        assert_eq!(history.zones(), (0..0, 0..3, 3..6));
+        assert!(history.iter().next().is_some_and(|first| first.content == "turn 2"));
+    }
+    
+    #[test]
+    fn test_compression_skipped_when_summary_saves_too_little() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("Short note.", "assistant", MessageType::ContextualInfo));
+        let before = history.current_tokens();
         
-        // Add the new item
-        self.items.push_back(item.clone());
-        self.current_tokens += item.token_count;
+        let mut outcome = PruneOutcome::default();
+        history.apply_compression(0, "Short note. [Contains: note] [Compressed]".to_string(), &mut outcome);
         
-        // Prune if necessary
-        self.intelligent_prune();
+        assert!(outcome.compressed.is_empty());
+        assert_eq!(history.current_tokens(), before);
+        assert_eq!(history.iter().next().map(|item| item.content.as_str()), Some("Short note."));
     }
     
-    /// Intelligent pruning based on token limits and importance
-    fn intelligent_prune(&mut self) {
-        if self.current_tokens <= self.max_tokens {
-            return;
-        }
+    #[test]
+    fn test_role_importance_floor_lifts_terse_system_messages() {
+        let config = OptimizationConfig {
+            role_importance_floor: [("system".to_string(), 0.6)].into_iter().collect(),
+            ..OptimizationConfig::default()
+        };
+        let history = OptimizedConversationHistory::with_config(config);
+        let now = now_timestamp();
         
-        // Strategy 1: Compress older messages (keep summary)
-        self.compress_old_messages();
+        let terse = item("Be concise.", "system", MessageType::ContextualInfo);
+        assert_eq!(history.importance_breakdown(&terse, now).score, 0.6);
+        let user = item("ok", "user", MessageType::ContextualInfo);
+        assert!(history.importance_breakdown(&user, now).score < 0.6);
+    }
+    
+    #[test]
+    fn test_soft_limit_compresses_without_removing() {
+        let config = OptimizationConfig {
+            max_tokens: 1_000,
+            min_messages: 1,
+            soft_limit_ratio: 0.5,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.set_full_retention_count(1);
         
-        // Strategy 2: Remove low-importance middle messages
-        if self.current_tokens > self.max_tokens {
-            self.remove_low_importance_messages();
+        for i in 0..7 {
+            let content = format!("Background note {}. {}", i, "lorem ipsum dolor sit amet ".repeat(11));
+            history.add_message(item(&content, "assistant", MessageType::ContextualInfo));
         }
         
-        // Strategy 3: Aggressive pruning (keep only essentials)
-        if self.current_tokens > self.max_tokens {
-            self.aggressive_prune();
-        }
+        assert_eq!(history.iter().count(), 7, "soft limit must not remove messages");
+        assert!(history.iter().any(|item| item.original_content.is_some()));
+        assert!(history.current_tokens() <= 500);
     }
     
-    /// Compress older messages to summaries
-    fn compress_old_messages(&mut self) {
-        let compress_threshold = self.items.len().saturating_sub(self.full_retention_count);
-        
-        for i in 0..compress_threshold {
-            if let Some(item) = self.items.get_mut(i) {
-                if item.content.len() > 200 && item.importance_score < 0.7 {
-                    let summary = self.create_summary(&item.content);
-                    let old_tokens = item.token_count;
-                    let new_tokens = self.estimate_tokens(&summary);
-                    
-                    item.content = summary;
-                    item.token_count = new_tokens;
-                    self.current_tokens = self.current_tokens - old_tokens + new_tokens;
-                }
-            }
-        }
+    #[test]
+    fn test_oversized_message_truncated_on_insert() {
+        let config = OptimizationConfig {
+            max_single_message_tokens: Some(50),
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        let log = "2024-01-01 INFO worker started\n".repeat(200);
+        
+        let outcome = history.add_message_reporting(item(&log, "tool", MessageType::ContextualInfo));
+        
+        assert_eq!(outcome.truncated, 1);
+        let stored = history.iter().next().unwrap();
+        assert!(stored.token_count <= 50);
+        assert!(stored.content.starts_with("2024-01-01 INFO worker started\n"));
+        assert!(stored.content.ends_with(TRUNCATION_MARKER));
     }
     
-    /// Remove messages with low importance (excluding recent ones)
-    fn remove_low_importance_messages(&mut self) {
-        let mut i = 0;
-        let keep_recent = self.min_messages;
-        let len = self.items.len();
+    #[test]
+    fn test_export_jsonl_writes_one_item_per_line() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        let mut first = item("first", "user", MessageType::UserQuery);
+        first.metadata.insert("request_id".to_string(), "r-1".to_string());
+        history.add_message(first);
+        history.add_message(item("second\nline", "assistant", MessageType::SystemResponse));
         
-        while i < len.saturating_sub(keep_recent) && self.current_tokens > self.max_tokens {
-            if let Some(item) = self.items.get(i) {
-                // Remove if low importance and not essential
-                if item.importance_score < 0.3 && !self.is_essential_message(item) {
-                    if let Some(removed) = self.items.remove(i) {
-                        self.current_tokens = self.current_tokens.saturating_sub(removed.token_count);
-                    }
-                    continue;
-                }
-            }
-            i += 1;
+        let mut out = Vec::new();
+        history.export_jsonl(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let parsed: ResponseItem = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(parsed.metadata.get("request_id").map(String::as_str), Some("r-1"));
+        assert!(lines[0].contains("importance_score"));
+    }
+    
+    #[test]
+    fn test_global_dedup_keeps_latest_occurrence() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        for content in ["ls output", "question", "ls output", "answer", "ls output"] {
+            history.add_message(item(content, "tool", MessageType::ContextualInfo));
         }
+        
+        assert_eq!(history.dedup(DedupMode::Consecutive), 0);
+        assert_eq!(history.dedup(DedupMode::Global { window: usize::MAX }), 2);
+        
+        let contents: Vec<&str> = history.iter().map(|item| item.content.as_str()).collect();
+        assert_eq!(contents, ["question", "answer", "ls output"]);
     }
     
-    /// Aggressive pruning - keep only the most essential messages
-    fn aggressive_prune(&mut self) {
-        // Keep only: recent messages + high importance + essential types
-        let target_count = self.min_messages.max(
-            (self.max_tokens / 1000).min(50) // Rough estimate: 1000 tokens per message average
+    #[test]
+    fn test_replace_message_keeps_tokens_in_sync() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("draft answer", "assistant", MessageType::SystemResponse));
+        
+        history.replace_message(0, "a revised and considerably longer answer".to_string()).unwrap();
+        
+        assert_eq!(history.iter().next().map(|item| item.content.as_str()), Some("a revised and considerably longer answer"));
+        assert_eq!(history.recompute_tokens(), 0);
+        assert_eq!(history.replace_message(5, String::new()), Err(IndexError { index: 5, len: 1 }));
+    }
+    
+    #[test]
+    fn test_compressed_flag_and_disabled_markers() {
+        let config = OptimizationConfig {
+            compressed_marker: None,
+            key_term_marker: None,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.add_message(item("Quoting the log: [Compressed] is printed here.", "user", MessageType::UserQuery));
+        assert_eq!(history.get_stats().compressed_messages, 0);
+        
+        let long = "The config error came from a stale cache entry. ".repeat(20);
+        let summary = history.create_summary(&long);
+        assert!(!summary.contains("[Compressed]") && !summary.contains("[Contains"));
+        
+        let mut outcome = PruneOutcome::default();
+        history.apply_compression(0, "Log quote.".to_string(), &mut outcome);
+        assert!(history.iter().next().unwrap().is_compressed);
+        assert_eq!(history.get_stats().compressed_messages, 1);
+        assert_eq!(history.compressed_messages().count(), 1);
+        assert_eq!(history.original_of(0), Some("Quoting the log: [Compressed] is printed here."));
+        
+        history.restore(0).unwrap();
+        assert_eq!(history.get_stats().compressed_messages, 0);
+    }
+    
+    #[test]
+    fn test_prune_target_leaves_headroom() {
+        let config = OptimizationConfig {
+            max_tokens: 1_000,
+            prune_strategy: PruneStrategy::SlidingWindow,
+            prune_target_ratio: 0.5,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        let content = "lorem ipsum dolor sit amet ".repeat(12);
+        
+        while history.add_message_reporting(item(&content, "user", MessageType::UserQuery)).removed.is_empty() {}
+        assert!(history.current_tokens() <= 500);
+        
+        let next = history.add_message_reporting(item(&content, "user", MessageType::UserQuery));
+        assert!(next.removed.is_empty(), "headroom should absorb the next insert");
+    }
+    
+    #[test]
+    fn test_for_model_reserves_completion_space() {
+        let history = OptimizedConversationHistory::for_model(ModelProfile::Gpt4o);
+        assert_eq!(history.max_tokens(), 128_000 - 16_384);
+        assert!(ModelProfile::Gpt4.max_tokens() >= MIN_MAX_TOKENS);
+    }
+    
+    #[test]
+    fn test_summary_keeps_head_and_tail() {
+        let history = OptimizedConversationHistory::new(100_000);
+        let content = format!(
+            "We looked into the flaky build. {}So the fix is pinning the toolchain.",
+            "Then we tried another cache setting and reran everything. ".repeat(10)
         );
         
-        if self.items.len() <= target_count {
-            return;
-        }
+        let summary = history.create_summary(&content);
+        assert!(summary.starts_with("We looked into the flaky build."));
+        assert!(summary.contains(" [...] So the fix is pinning the toolchain."));
+        assert!(history.estimate_tokens(&summary) < history.estimate_tokens(&content) / 2);
+    }
+    
+    #[test]
+    fn test_summary_keeps_preserved_spans() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.config.summary_preserve_patterns = vec![
+            Regex::new(r"\bE\d{4}\b").unwrap(),
+            Regex::new(r"src/[\w/]+\.rs").unwrap(),
+        ];
+        let content = format!(
+            "An error occurred. {}The compiler reported E0382 in src/engine/state.rs there. {}We will retry.",
+            "Some context about the build. ".repeat(8),
+            "More notes on the investigation. ".repeat(8)
+        );
         
-        // Sort by importance (keep originals in place, work with indices)
-        let mut importance_indices: Vec<_> = (0..self.items.len()).collect();
-        importance_indices.sort_by(|&a, &b| {
-            let item_a = &self.items[a];
-            let item_b = &self.items[b];
-            
-            // Recent messages get priority boost
-            let recency_boost_a = if a >= self.items.len() - self.min_messages { 1.0 } else { 0.0 };
-            let recency_boost_b = if b >= self.items.len() - self.min_messages { 1.0 } else { 0.0 };
-            
-            let score_a = item_a.importance_score + recency_boost_a;
-            let score_b = item_b.importance_score + recency_boost_b;
-            
-            score_b.partial_cmp(&score_a).unwrap_or(std::cmp::Ordering::Equal)
-        });
+        let summary = history.create_summary(&content);
+        assert!(summary.contains(" [...] "));
+        assert!(summary.contains("E0382"));
+        assert!(summary.contains("src/engine/state.rs"));
+        
+        let json = serde_json::to_string(&history.config).unwrap();
+        let config: OptimizationConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.summary_preserve_patterns[1].as_str(), r"src/[\w/]+\.rs");
+    }
+    
+    #[test]
+    fn test_rescore_where_touches_only_matches() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("Be concise.", "system", MessageType::ContextualInfo));
+        history.add_message(item("ok", "user", MessageType::ContextualInfo));
+        let user_before = history.iter().nth(1).unwrap().importance_score;
         
-        // Keep only the top items
-        let keep_indices = &importance_indices[..target_count];
-        let mut new_items = VecDeque::new();
-        let mut new_token_count = 0;
+        history.config.role_importance_floor.insert("system".to_string(), 0.9);
+        history.config.role_importance_floor.insert("user".to_string(), 0.9);
+        history.rescore_where(|item| item.role == "system");
         
-        // Preserve chronological order
-        for i in 0..self.items.len() {
-            if keep_indices.contains(&i) {
-                if let Some(item) = self.items.get(i) {
-                    new_items.push_back(item.clone());
-                    new_token_count += item.token_count;
-                }
+        assert_eq!(history.iter().next().unwrap().importance_score, 0.9);
+        assert_eq!(history.iter().nth(1).unwrap().importance_score, user_before);
+    }
+    
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "token accounting underflow")]
+    fn test_token_underflow_panics_in_debug() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("hello", "user", MessageType::UserQuery));
+        history.current_tokens = 0; // simulate a bookkeeping desync
+        history.keep_last(0);
+    }
+    
+    #[test]
+    fn test_quoted_messages_get_reference_boost() {
+        let config = OptimizationConfig {
+            reference_boost: 0.2,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.add_message(item("Retry the upload with a smaller chunk size.", "assistant", MessageType::ContextualInfo));
+        history.add_message(item("Unrelated chatter about lunch plans today.", "assistant", MessageType::ContextualInfo));
+        let before: Vec<f64> = history.iter().map(|item| item.importance_score).collect();
+        
+        history.add_message(item("You said \"a smaller chunk size\" earlier, how small?", "user", MessageType::UserQuery));
+        
+        let after: Vec<f64> = history.iter().map(|item| item.importance_score).collect();
+        assert!((after[0] - before[0] - 0.2).abs() < 1e-9);
+        assert_eq!(after[1], before[1]);
+    }
+    
+    #[test]
+    fn test_trim_to_restores_limit() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.set_min_messages(2);
+        for i in 0..20 {
+            history.add_message(item(&format!("Message number {} with some filler text", i), "assistant", MessageType::ContextualInfo));
+        }
+        
+        let report = history.trim_to(60);
+        assert!(history.current_tokens() <= 60);
+        assert!(!report.removed_indices.is_empty());
+        assert_eq!(report.projected_tokens, history.current_tokens());
+        assert_eq!(history.max_tokens(), 100_000);
+    }
+    
+    #[test]
+    fn test_passthrough_baseline_never_prunes() {
+        fn run(history: &mut dyn History) -> ConversationStats {
+            for i in 0..50 {
+                history.add_message(item(&format!("Status update {} with filler text", i), "assistant", MessageType::ContextualInfo));
             }
+            history.get_stats()
         }
         
-        self.items = new_items;
-        self.current_tokens = new_token_count;
+        let mut optimized = OptimizedConversationHistory::new(200);
+        optimized.set_min_messages(2);
+        let optimized_stats = run(&mut optimized);
+        let baseline_stats = run(&mut PassthroughHistory::new(200));
+        
+        assert_eq!(baseline_stats.total_messages, 50);
+        assert!(baseline_stats.over_budget_floor);
+        assert!(optimized_stats.total_tokens < baseline_stats.total_tokens);
     }
     
-    /// Calculate importance score for a message
-    fn calculate_importance(&self, item: &ResponseItem) -> f64 {
-        let mut score = 0.5; // Base score
+    #[test]
+    fn test_compress_now_never_removes() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.set_full_retention_count(1);
+        for i in 0..3 {
+            let content = format!("Background note {}. {}", i, "lorem ipsum dolor sit amet ".repeat(11));
+            history.add_message(item(&content, "assistant", MessageType::ContextualInfo));
+        }
         
-        // Message type scoring
-        score += match item.message_type {
-            MessageType::ImportantDecision => 0.4,
-            MessageType::ErrorHandling => 0.3,
-            MessageType::UserQuery => 0.2,
-            MessageType::CodeExecution => 0.1,
-            MessageType::SystemResponse => 0.0,
-            MessageType::ContextualInfo => -0.1,
+        let outcome = history.compress_now();
+        assert!(outcome.removed.is_empty());
+        assert_eq!(outcome.compressed.len(), 2);
+        assert_eq!(history.iter().count(), 3);
+    }
+    
+    #[test]
+    fn test_set_tokenizer_recounts_existing_messages() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.add_message(item("abcdefghij", "user", MessageType::UserQuery));
+        
+        history.set_tokenizer(CharRatioTokenizer::new(1.0));
+        assert_eq!(history.iter().next().unwrap().token_count, 10);
+        assert_eq!(history.recompute_tokens(), 0);
+        assert_eq!(history.current_tokens(), 10 + history.config.per_message_overhead);
+    }
+    
+    #[test]
+    fn test_full_retention_ratio_follows_tokens() {
+        let config = OptimizationConfig {
+            full_retention_ratio: Some(0.3),
+            ..OptimizationConfig::default()
         };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        // One long message near the end outweighs many short ones
+        for i in 0..8 {
+            let mut message = item(&format!("turn {}", i), "user", MessageType::UserQuery);
+            message.token_count = if i == 6 { 60 } else { 6 };
+            history.insert_item(message).unwrap();
+        }
         
-        // Content-based scoring
-        let content_lower = item.content.to_lowercase();
+        // 134 tokens in total: the last two (10 + 64) cover the 40-token share
+        assert_eq!(history.full_zone_start(), 6);
+    }
+    
+    #[test]
+    fn test_split_large_messages_keeps_text_and_order() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        let report = format!("{}Conclusion: ship it.", "Verbose detail line.\n".repeat(40));
+        history.add_message(item(&report, "assistant", MessageType::SystemResponse));
+        history.add_message(item("thanks", "user", MessageType::UserQuery));
         
-        // High-value keywords
-        if content_lower.contains("error") || content_lower.contains("bug") {
-            score += 0.2;
-        }
-        if content_lower.contains("important") || content_lower.contains("critical") {
-            score += 0.2;
+        assert_eq!(history.split_large_messages(50), 1);
+        
+        let chunks: Vec<&ResponseItem> = history.iter().filter(|item| item.role == "assistant").collect();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.token_count <= 50 && chunk.group_id.is_none()));
+        assert_eq!(chunks.iter().map(|chunk| chunk.content.as_str()).collect::<String>(), report);
+        assert_eq!(history.iter().next_back().unwrap().content, "thanks");
+        assert_eq!(history.recompute_tokens(), 0);
+    }
+    
+    #[test]
+    fn test_oversized_policy_reject_and_truncate() {
+        let huge = "word ".repeat(2_000);
+        let config = OptimizationConfig {
+            max_tokens: 1_000,
+            oversized_policy: OversizedPolicy::Reject,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config.clone());
+        let err = history.try_add_message(item(&huge, "tool", MessageType::ContextualInfo)).unwrap_err();
+        assert_eq!(err.max_tokens, 1_000);
+        assert_eq!(history.add_message_reporting(item(&huge, "tool", MessageType::ContextualInfo)).rejected, 1);
+        assert_eq!(history.iter().count(), 0);
+        
+        let mut history = OptimizedConversationHistory::with_config(OptimizationConfig {
+            oversized_policy: OversizedPolicy::Truncate,
+            ..config
+        });
+        let outcome = history.add_message_reporting(item(&huge, "tool", MessageType::ContextualInfo));
+        assert_eq!(outcome.truncated, 1);
+        assert!(history.current_tokens() <= 1_000);
+    }
+    
+    #[test]
+    fn test_word_aware_tokenizer_calibration() {
+        // Reference counts from cl100k_base (GPT-4)
+        let samples = [
+            ("hello world", 2),
+            ("Hello, world!", 4),
+            ("tiktoken is great!", 6),
+            ("The quick brown fox jumps over the lazy dog.", 10),
+        ];
+        let tokenizer = WordAwareTokenizer::default();
+        
+        let mut estimated = 0;
+        for (text, actual) in samples {
+            let count = tokenizer.count(text);
+            assert!(count.abs_diff(actual) <= 1, "{:?}: estimated {}, actual {}", text, count, actual);
+            estimated += count;
         }
-        if content_lower.contains("solution") || content_lower.contains("fix") {
-            score += 0.15;
+        let actual: usize = samples.iter().map(|&(_, count)| count).sum();
+        assert!(estimated.abs_diff(actual) * 10 <= actual * 2, "total {} vs {}", estimated, actual);
+    }
+    
+    #[test]
+    fn test_disabled_compression_only_removes() {
+        let config = OptimizationConfig {
+            max_tokens: 500,
+            min_messages: 2,
+            enable_compression: false,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        for i in 0..10 {
+            let content = format!("Background note {}. {}", i, "lorem ipsum dolor sit amet ".repeat(11));
+            history.add_message(item(&content, "assistant", MessageType::ContextualInfo));
         }
         
-        // Code presence
-        if item.content.contains("```") || item.content.contains("fn ") {
-            score += 0.1;
+        assert!(history.current_tokens() <= 500);
+        assert!(history.iter().count() < 10);
+        assert_eq!(history.compressed_messages().count(), 0);
+    }
+    
+    #[test]
+    fn test_clear_keeps_config_and_hooks() {
+        let removed = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&removed);
+        let mut history = OptimizedConversationHistory::new(50);
+        history.set_min_messages(1);
+        history.on_prune(move |event| {
+            if let PruneEvent::Removed { .. } = event {
+                *counter.lock().unwrap() += 1;
+            }
+        });
+        for i in 0..10 {
+            history.add_message(item(&format!("message number {} with some words", i), "user", MessageType::UserQuery));
         }
+        let pruned = *removed.lock().unwrap();
+        assert!(pruned > 0);
+        
+        history.clear();
+        let stats = history.get_stats();
+        assert_eq!(stats.total_messages, 0);
+        assert_eq!(stats.total_tokens, 0);
+        assert_eq!(history.telemetry(), PruneTelemetry::default());
+        assert_eq!(*removed.lock().unwrap(), pruned);
         
-        // Length penalty for very long messages (likely verbose)
-        if item.content.len() > 2000 {
-            score -= 0.1;
+        for i in 0..10 {
+            history.add_message(item(&format!("message number {} with some words", i), "user", MessageType::UserQuery));
+        }
+        assert_eq!(history.max_tokens, 50);
+        assert!(*removed.lock().unwrap() > pruned);
+    }
+    
+    #[test]
+    fn test_preserve_one_per_type_keeps_last_representative() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.config.min_messages = 0;
+        history.add_message(item("use the staging database", "assistant", MessageType::ImportantDecision));
+        history.add_message(item("what next", "user", MessageType::UserQuery));
+        history.add_message(item("and then", "user", MessageType::UserQuery));
+        for (item, score) in history.items.iter_mut().zip([0.1, 0.2, 0.25]) {
+            item.importance_score = score;
         }
+        history.max_tokens = 1;
         
-        // Recency boost (more recent = slightly higher score)
-        let now = chrono::Utc::now();
-        let age_minutes = now.signed_duration_since(item.timestamp).num_minutes();
-        if age_minutes < 60 {
-            score += 0.1;
+        let mut unprotected = history.clone();
+        assert_eq!(unprotected.remove_low_importance().removed.len(), 3);
+        
+        history.config.preserve_one_per_type = true;
+        let outcome = history.remove_low_importance();
+        assert_eq!(outcome.removed.len(), 1);
+        assert_eq!(outcome.removed[0].content, "what next");
+        let kept: Vec<&str> = history.iter().map(|item| item.content.as_str()).collect();
+        assert_eq!(kept, ["use the staging database", "and then"]);
+    }
+    
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn test_stats_published_on_add_and_prune() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.config.min_messages = 0;
+        history.add_message(item("before anyone listens", "user", MessageType::UserQuery));
+        
+        let mut receiver = history.stats_sender().subscribe();
+        history.add_message(item("first update", "user", MessageType::UserQuery));
+        history.trim_to(1);
+        
+        assert_eq!(receiver.try_recv().unwrap().total_messages, 2);
+        let trimmed = receiver.try_recv().unwrap();
+        assert_eq!(trimmed.max_tokens, 100_000);
+        assert!(trimmed.total_messages < 2);
+        assert!(receiver.try_recv().is_err());
+        
+        history.add_message(item("second update", "user", MessageType::UserQuery));
+        receiver.try_recv().unwrap();
+        history.simulate_prune();
+        assert!(receiver.try_recv().is_err());
+    }
+    
+    #[test]
+    fn test_type_implied_keywords_not_counted_twice() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        let now = now_timestamp();
+        let decision = item("important: ship on friday", "assistant", MessageType::ImportantDecision);
+        let note = item("important: ship on friday", "assistant", MessageType::ContextualInfo);
+        
+        assert!(history.importance_breakdown(&decision, now).keyword_hits.is_empty());
+        assert_eq!(history.importance_breakdown(&note, now).keywords, 0.2);
+        
+        history.config.type_implied_keywords.clear();
+        assert_eq!(history.importance_breakdown(&decision, now).keywords, 0.2);
+    }
+    
+    #[test]
+    fn test_type_implied_keywords_are_case_folded() {
+        let mut config = OptimizationConfig::default();
+        config.keyword_weights.insert("Deadline".to_string(), 0.3);
+        config.type_implied_keywords.insert(MessageType::ImportantDecision, vec!["DEADLINE".to_string()]);
+        let history = OptimizedConversationHistory::with_config(config);
+        let decision = item("the deadline moves to friday", "assistant", MessageType::ImportantDecision);
+        
+        assert!(history.importance_breakdown(&decision, now_timestamp()).keyword_hits.is_empty());
+    }
+    
+    #[test]
+    fn test_aggressive_prune_fills_budget_with_short_messages() {
+        let mut history = OptimizedConversationHistory::builder()
+            .max_tokens(200)
+            .min_messages(1)
+            .build();
+        
+        for i in 0..100 {
+            history.add_message(item(&format!("message {}", i), "assistant", MessageType::ContextualInfo));
         }
         
-        score.clamp(0.0, 1.0)
+        assert!(history.current_tokens() <= 200);
+        assert!(history.export_for_analysis().len() > 10,
+                "short messages over-pruned: {} kept", history.export_for_analysis().len());
     }
     
-    /// Check if a message is essential and should never be removed
-    fn is_essential_message(&self, item: &ResponseItem) -> bool {
-        let content_lower = item.content.to_lowercase();
+    #[test]
+    fn test_token_bookkeeping_never_drifts() {
+        let mut history = OptimizedConversationHistory::builder()
+            .max_tokens(3_000)
+            .min_messages(3)
+            .full_retention_count(5)
+            .build();
         
-        // System messages
-        if content_lower.starts_with("system:") {
-            return true;
+        for i in 0..300 {
+            let (role, message_type) = match i % 3 {
+                0 => ("user", MessageType::UserQuery),
+                1 => ("assistant", MessageType::SystemResponse),
+                _ => ("tool", MessageType::ContextualInfo),
+            };
+            let content = format!("Message {}. {}", i, "detail ".repeat(i % 70));
+            history.add_message(item(&content, role, message_type));
+            assert_eq!(history.recompute_tokens(), 0, "drift after message {}", i);
+            assert!(history.is_chronologically_ordered());
         }
+    }
+    
+    #[test]
+    fn test_split_code_fences_keeps_blocks_intact() {
+        let content = "Intro text.\n```rust\nfn main() {}\n```\nMiddle.\n```\nunclosed";
+        let (prose, blocks) = split_code_fences(content);
+        
+        assert_eq!(prose, "Intro text.\n\nMiddle.\n");
+        assert_eq!(blocks, ["```rust\nfn main() {}\n```", "```\nunclosed"]);
+    }
+    
+    #[test]
+    fn test_code_markers_match_at_word_start() {
+        assert!(contains_code_marker("fn main() {}", "fn "));
+        assert!(contains_code_marker("see: fn parse(x)", "fn "));
+        assert!(!contains_code_marker("the elfn sat down", "fn "));
+        assert!(contains_code_marker("text```rust", "```"));
+    }
+    
+    #[test]
+    #[cfg(feature = "chrono")]
+    fn test_merge_interleaves_by_timestamp() {
+        let start = chrono::Utc::now();
+        let at = |minutes: i64, content: &str| {
+            let mut item = item(content, "assistant", MessageType::SystemResponse);
+            item.timestamp = start + chrono::Duration::minutes(minutes);
+            item
+        };
+        
+        let mut left = OptimizedConversationHistory::new(100_000);
+        left.add_message(at(0, "a0"));
+        left.add_message(at(2, "a2"));
+        let mut right = OptimizedConversationHistory::new(100_000);
+        right.add_message(at(1, "b1"));
+        right.add_message(at(3, "b3"));
+        
+        left.merge(right);
         
-        // Error messages
-        if content_lower.contains("error:") || content_lower.contains("exception") {
-            return true;
+        let contents: Vec<&str> = left.export_for_analysis().into_iter().map(|item| item.content.as_str()).collect();
+        assert_eq!(contents, ["a0", "b1", "a2", "b3"]);
+        assert!(left.is_chronologically_ordered());
+        assert_eq!(left.recompute_tokens(), 0);
+    }
+    
+    #[test]
+    #[cfg(feature = "chrono")]
+    fn test_frozen_clock_gives_stable_scores() {
+        let frozen = chrono::Utc::now();
+        let config = OptimizationConfig {
+            max_tokens: 10_000,
+            recency_half_life_minutes: LEGACY_RECENCY_CLIFF,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config).with_clock(move || frozen);
+        
+        let mut fresh = item("How do I fix this?", "user", MessageType::UserQuery);
+        fresh.timestamp = frozen - chrono::Duration::minutes(59);
+        let mut stale = fresh.clone();
+        stale.timestamp = frozen - chrono::Duration::minutes(61);
+        
+        history.add_message(stale);
+        history.add_message(fresh);
+        
+        let items = history.export_for_analysis();
+        assert!((items[0].importance_score - 0.85).abs() < 1e-9);
+        assert!((items[1].importance_score - 0.95).abs() < 1e-9);
+    }
+    
+    #[test]
+    fn test_per_type_budget_drops_lowest_importance_first() {
+        let mut config = OptimizationConfig {
+            max_tokens: 1_000_000,
+            min_messages: 1,
+            ..OptimizationConfig::default()
+        };
+        config.per_type_budgets.insert(MessageType::CodeExecution, 100);
+        let mut history = OptimizedConversationHistory::with_config(config);
+        
+        let dump = format!("```\n{}\n```", "let x = 1;".repeat(20));
+        history.add_message(item(&dump, "assistant", MessageType::CodeExecution));
+        history.add_message(item(&format!("critical fix {}", dump), "assistant", MessageType::CodeExecution));
+        history.add_message(item("What next?", "user", MessageType::UserQuery));
+        
+        let items = history.export_for_analysis();
+        assert_eq!(items.len(), 2);
+        assert!(items[0].content.starts_with("critical fix"));
+    }
+    
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn test_tiktoken_picks_encoding_per_model() {
+        let gpt4o = TiktokenTokenizer::new("gpt-4o");
+        assert_eq!(gpt4o.encoding(), "o200k_base");
+        assert_eq!(TiktokenTokenizer::new("gpt-4").encoding(), "cl100k_base");
+        assert_eq!(TiktokenTokenizer::new("some-local-model").encoding(), "cl100k_base");
+        
+        assert_eq!(gpt4o.count("hello world"), 2);
+        assert_eq!(gpt4o.count(""), 0);
+    }
+    
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_save_and_load_round_trip() {
+        let dir = std::env::temp_dir().join(format!("history-round-trip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.json");
+        
+        let mut history = OptimizedConversationHistory::new(10_000);
+        history.add_message(item("Deploy failed: error in step 3", "assistant", MessageType::ErrorHandling));
+        history.add_message(item("Retry with a clean cache", "user", MessageType::UserQuery));
+        assert!(history.pin_message(0));
+        history.save_to_path(&path).unwrap();
+        
+        let loaded = OptimizedConversationHistory::load_from_path(&path).unwrap();
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(leftovers, 1, "temporary file left behind");
+        
+        let (saved, restored) = (history.export_for_analysis(), loaded.export_for_analysis());
+        assert_eq!(restored.len(), saved.len());
+        for (before, after) in saved.iter().zip(&restored) {
+            assert_eq!(after.content, before.content);
+            assert_eq!(after.token_count, before.token_count);
+            assert_eq!(after.pinned, before.pinned);
         }
+        assert_eq!(loaded.get_stats().total_tokens, history.get_stats().total_tokens);
+    }
+    
+    #[test]
+    fn test_sliding_window_skips_pinned_messages() {
+        let config = OptimizationConfig {
+            max_tokens: 2_000,
+            prune_strategy: PruneStrategy::SlidingWindow,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        let schema = format!("Tool schema definition: {}", "x".repeat(400));
+        history.add_message(item(&schema, "developer", MessageType::ContextualInfo));
+        assert!(history.pin_message(0));
         
-        // Important decisions or configurations
-        if content_lower.contains("config") || content_lower.contains("setting") {
-            return true;
+        for i in 0..200 {
+            let filler = format!("Filler chatter {} {}", i, "y".repeat(300));
+            history.add_message(item(&filler, "assistant", MessageType::ContextualInfo));
         }
         
-        false
+        let items = history.export_for_analysis();
+        assert!(items.len() < 201, "sliding window never evicted anything");
+        assert_eq!(items[0].content, schema, "pinned message was evicted");
+        assert!(history.get_stats().total_tokens <= 2_000);
     }
     
-    /// Create a summary of content for compression
-    fn create_summary(&self, content: &str) -> String {
-        if content.len() <= 200 {
-            return content.to_string();
+    #[test]
+    fn test_message_type_serializes_as_plain_string() {
+        let types = [
+            MessageType::UserQuery,
+            MessageType::SystemResponse,
+            MessageType::CodeExecution,
+            MessageType::ImportantDecision,
+            MessageType::ErrorHandling,
+            MessageType::ContextualInfo,
+            MessageType::Custom("ToolCall".to_string()),
+        ];
+        
+        for message_type in types {
+            let json = serde_json::to_string(&message_type).unwrap();
+            assert_eq!(json, format!("\"{}\"", message_type.as_str()));
+            let parsed: MessageType = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, message_type);
         }
+    }
+    
+    #[test]
+    #[cfg(feature = "chrono")]
+    fn test_late_message_is_inserted_in_order() {
+        let mut history = OptimizedConversationHistory::new(10_000);
+        let first = item("First question", "user", MessageType::UserQuery);
+        let mut late = item("Delayed tool reply", "tool", MessageType::CodeExecution);
+        late.timestamp = first.timestamp - chrono::Duration::minutes(5);
         
-        // Simple summarization: first sentence + key points
-        let first_sentence = content
-            .split(". ")
-            .next()
-            .unwrap_or("")
-            .to_string();
+        history.add_message(first);
+        history.add_message(item("Second question", "user", MessageType::UserQuery));
+        history.add_message(late);
         
-        let mut summary = first_sentence;
+        let contents: Vec<&str> = history.export_for_analysis().iter()
+            .map(|item| item.content.as_str())
+            .collect();
+        assert_eq!(contents, ["Delayed tool reply", "First question", "Second question"]);
+        assert!(history.is_chronologically_ordered());
+    }
+    
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_load_with_config_recounts_overhead() {
+        let dir = std::env::temp_dir().join(format!("history-load-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.json");
         
-        // Add key technical terms if present
-        let key_terms = ["error", "function", "variable", "config", "solution", "result"];
-        for term in &key_terms {
-            if content.to_lowercase().contains(term) && !summary.to_lowercase().contains(term) {
-                summary.push_str(&format!(" [Contains: {}]", term));
+        let mut history = OptimizedConversationHistory::new(10_000);
+        history.add_message(item("Which port does the API use?", "user", MessageType::UserQuery));
+        history.add_message(item("It listens on 8080", "assistant", MessageType::SystemResponse));
+        history.save_to_path(&path).unwrap();
+        
+        let config = OptimizationConfig {
+            max_tokens: 10_000,
+            per_message_overhead: 10,
+            ..OptimizationConfig::default()
+        };
+        let options = LoadOptions { config: Some(config), rescore: false };
+        let loaded = OptimizedConversationHistory::load_from_path_with(&path, options).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        
+        let expected: usize = loaded.export_for_analysis().iter().map(|item| item.token_count + 10).sum();
+        assert_eq!(loaded.current_tokens(), expected);
+    }
+    
+    #[test]
+    #[cfg(feature = "chrono")]
+    fn test_remove_before_keeps_pinned_messages() {
+        let mut history = OptimizedConversationHistory::new(10_000);
+        let now = chrono::Utc::now();
+        let mut chatter = item("Old chatter", "assistant", MessageType::ContextualInfo);
+        chatter.timestamp = now - chrono::Duration::hours(3);
+        let mut schema = item("Tool schema definition", "developer", MessageType::ContextualInfo);
+        schema.timestamp = now - chrono::Duration::hours(2);
+        
+        history.add_message(chatter);
+        history.add_message(schema);
+        assert!(history.pin_message(1));
+        history.add_message(item("Fresh question", "user", MessageType::UserQuery));
+        
+        assert_eq!(history.remove_before(now - chrono::Duration::hours(1)), 1);
+        let contents: Vec<&str> = history.export_for_analysis().iter()
+            .map(|item| item.content.as_str())
+            .collect();
+        assert_eq!(contents, ["Tool schema definition", "Fresh question"]);
+        assert_eq!(history.recompute_tokens(), 0);
+    }
+    
+    /// Drive a future that never actually waits (test summarizers are `ready`)
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct NoopWake;
+        impl std::task::Wake for NoopWake {
+            fn wake(self: Arc<Self>) {}
+        }
+        
+        let waker = std::task::Waker::from(Arc::new(NoopWake));
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
             }
         }
+    }
+    
+    #[test]
+    fn test_async_compression_keeps_code_blocks() {
+        let mut history = OptimizedConversationHistory::new(100_000);
+        history.set_full_retention_count(0);
+        let code = "```rust\nfn main() {\n    run();\n}\n```";
+        let content = format!("{}{}", "We walked through the whole startup sequence step by step. ".repeat(6), code);
+        history.add_message(item(&content, "assistant", MessageType::ContextualInfo));
+        history.max_tokens = history.current_tokens - 1;
         
-        // Add compressed indicator
-        summary.push_str(" [Compressed]");
+        block_on(history.intelligent_prune_async(|prose: String| {
+            assert!(!prose.contains("```"));
+            std::future::ready("Startup walkthrough.".to_string())
+        }));
         
-        summary
+        let items = history.export_for_analysis();
+        assert_eq!(items[0].content, format!("Startup walkthrough.\n{}", code));
     }
     
-    /// Estimate token count for text (rough approximation)
-    fn estimate_tokens(&self, text: &str) -> usize {
-        // Rough estimate: ~4 characters per token for English
-        // More conservative estimate for mixed content
-        (text.len() as f64 / 3.5).ceil() as usize
+    #[test]
+    fn test_max_messages_drops_oldest_non_essential() {
+        let config = OptimizationConfig {
+            max_tokens: 1_000_000,
+            min_messages: 2,
+            max_messages: Some(5),
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.add_message(item("SYSTEM: sandboxed workspace", "system", MessageType::ContextualInfo));
+        for i in 0..10 {
+            history.add_message(item(&format!("Step {} done", i), "assistant", MessageType::SystemResponse));
+        }
+        
+        let contents: Vec<&str> = history.export_for_analysis().iter()
+            .map(|item| item.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            ["SYSTEM: sandboxed workspace", "Step 6 done", "Step 7 done", "Step 8 done", "Step 9 done"]
+        );
     }
     
-    /// Get current token usage statistics
-    pub fn get_stats(&self) -> ConversationStats {
-        ConversationStats {
-            total_messages: self.items.len(),
-            total_tokens: self.current_tokens,
-            max_tokens: self.max_tokens,
-            utilization_percentage: (self.current_tokens as f64 / self.max_tokens as f64 * 100.0) as u32,
-            compressed_messages: self.items.iter()
-                .filter(|item| item.content.contains("[Compressed]"))
-                .count(),
-            high_importance_messages: self.items.iter()
-                .filter(|item| item.importance_score > 0.7)
-                .count(),
+    #[test]
+    fn test_essential_messages_compressed_to_cap() {
+        let config = OptimizationConfig {
+            max_tokens: 2_000,
+            min_messages: 0,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.set_full_retention_count(0);
+        
+        for i in 0..16 {
+            let trace = format!("error: build {} failed. {}", i, "Stack frame in the compile step. ".repeat(18));
+            history.add_message(item(&trace, "tool", MessageType::ErrorHandling));
         }
+        
+        let items = history.export_for_analysis();
+        assert_eq!(items.len(), 16, "essential messages must be compressed, not removed");
+        assert!(items.iter().any(|item| item.original_content.is_some()));
+        assert!(history.current_tokens() <= 2_000);
+        assert!(!history.get_stats().essential_over_cap);
     }
     
-    /// Export conversation for analysis
-    pub fn export_for_analysis(&self) -> Vec<&ResponseItem> {
-        self.items.iter().collect()
+    #[test]
+    fn test_free_space_stops_at_soft_limit() {
+        let config = OptimizationConfig {
+            max_tokens: 1_000,
+            soft_limit_ratio: 0.5,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.add_message(item("Short note", "user", MessageType::UserQuery));
+        
+        let room = 500 - history.current_tokens();
+        assert_eq!(history.free_space(), room);
+        let overhead = history.config.per_message_overhead;
+        assert!(history.can_fit(room - overhead));
+        assert!(!history.can_fit(room - overhead + 1));
+    }
+    
+    #[test]
+    #[cfg(feature = "binary")]
+    fn test_binary_round_trip_preserves_items_and_config() {
+        let dir = std::env::temp_dir().join(format!("history-binary-round-trip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.msgpack");
+        
+        let config = OptimizationConfig {
+            max_tokens: 10_000,
+            per_message_overhead: 7,
+            soft_limit_ratio: 0.8,
+            ..OptimizationConfig::default()
+        };
+        let mut history = OptimizedConversationHistory::with_config(config);
+        history.add_message(item("Deploy failed: error in step 3", "assistant", MessageType::ErrorHandling));
+        history.add_message(item("Retry with a clean cache", "user", MessageType::Custom("Retry".to_string())));
+        assert!(history.pin_message(0));
+        history.save_binary(&path).unwrap();
+        
+        let loaded = OptimizedConversationHistory::load_binary(&path).unwrap();
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(leftovers, 1, "temporary file left behind");
+        
+        assert_eq!(loaded.max_tokens(), 10_000);
+        assert_eq!(loaded.config.per_message_overhead, 7);
+        assert_eq!(loaded.config.soft_limit_ratio, 0.8);
+        
+        let (saved, restored) = (history.export_for_analysis(), loaded.export_for_analysis());
+        assert_eq!(restored.len(), saved.len());
+        for (before, after) in saved.iter().zip(&restored) {
+            assert_eq!(after.content, before.content);
+            assert_eq!(after.role, before.role);
+            assert_eq!(after.message_type, before.message_type);
+            assert_eq!(after.token_count, before.token_count);
+            assert_eq!(after.importance_score, before.importance_score);
+            assert_eq!(after.pinned, before.pinned);
+        }
+        assert_eq!(loaded.current_tokens(), history.current_tokens());
+    }
+    
+    #[test]
+    fn test_installed_time_source_drives_now_timestamp() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        
+        // Process-wide, so the source only counts and defers to the system clock
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        assert!(set_time_source(|| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            system_now()
+        }));
+        assert!(!set_time_source(system_now));
+        
+        let before = CALLS.load(Ordering::SeqCst);
+        now_timestamp();
+        assert!(CALLS.load(Ordering::SeqCst) > before);
+        
+        let before = CALLS.load(Ordering::SeqCst);
+        let mut history = OptimizedConversationHistory::new(10_000);
+        history.add_message(item("hello from the browser", "user", MessageType::UserQuery));
+        assert!(CALLS.load(Ordering::SeqCst) > before);
     }
-}
-
-#[derive(Debug, Serialize)]
-pub struct ConversationStats {
-    pub total_messages: usize,
-    pub total_tokens: usize,
-    pub max_tokens: usize,
-    pub utilization_percentage: u32,
-    pub compressed_messages: usize,
-    pub high_importance_messages: usize,
 }
 
 // 💕 Implementation Notes for Phase 2:
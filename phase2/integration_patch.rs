@@ -12,20 +12,74 @@
 // 1. Add dependencies to Cargo.toml:
 /*
 [dependencies]
-chrono = { version = "0.4", features = ["serde"] }
+chrono = { version = "0.4", features = ["serde"], optional = true }
 serde = { version = "1.0", features = ["derive"] }
+serde_json = "1.0"
+log = "0.4"
+regex = "1"
+tiktoken-rs = { version = "0.6", optional = true }
+rmp-serde = { version = "1.3", optional = true }
+tokio = { version = "1", features = ["sync"], optional = true }
+tokio-stream = { version = "0.1", features = ["sync"], optional = true }
+
+# Browser builds: chrono reads the clock through js-sys
+[target.'cfg(all(target_arch = "wasm32", target_os = "unknown"))'.dependencies]
+chrono = { version = "0.4", features = ["serde", "wasmbind"], optional = true }
+
+[features]
+default = ["chrono", "fs"]
+chrono = ["dep:chrono"]          # off: timestamps are u64 epoch millis (call set_time_source on wasm)
+fs = []                          # save_to_path / load_from_path; off for wasm32-unknown-unknown
+tiktoken = ["dep:tiktoken-rs"]   # exact GPT-4/GPT-4o token counts
+binary = ["fs", "dep:rmp-serde"] # save_binary / load_binary (MessagePack)
+tokio = ["dep:tokio", "dep:tokio-stream"]  # stats_stream push updates
 */
 
 // 2. Replace the existing ConversationHistory struct:
 
 use crate::ai::openai_api::ResponseItem as OriginalResponseItem;
-use std::collections::VecDeque;
-use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
 use serde::{Serialize, Deserialize};
 
+/// Roles accepted by `try_record_items`
+pub const KNOWN_ROLES: &[&str] = &["system", "developer", "user", "assistant", "tool", "function"];
+
+/// Why `try_record_items` rejected a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    EmptyRole { index: usize },
+    UnknownRole { index: usize, role: String },
+    EmptyContent { index: usize },
+}
+
+impl std::fmt::Display for RecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyRole { index } => write!(f, "item {} has an empty role", index),
+            Self::UnknownRole { index, role } => write!(
+                f,
+                "item {} has unknown role '{}' (expected one of {:?})",
+                index, role, KNOWN_ROLES
+            ),
+            Self::EmptyContent { index } => write!(f, "item {} has empty content", index),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+/// Default `with_code_fraction_threshold`: over 30% fenced code makes an assistant reply `CodeExecution`
+pub const DEFAULT_CODE_FRACTION_THRESHOLD: f64 = 0.3;
+
+/// Caller-supplied (content, role) → MessageType classifier
+pub type MessageClassifier = Box<dyn Fn(&str, &str) -> MessageType + Send + Sync>;
+
 // 🔄 Compatibility wrapper to preserve existing API
 pub(crate) struct ConversationHistory {
     optimized: OptimizedConversationHistory,
+    classifier: Option<MessageClassifier>,
+    role_types: HashMap<String, MessageType>,
+    code_fraction_threshold: f64,
 }
 
 impl ConversationHistory {
@@ -34,6 +88,9 @@ impl ConversationHistory {
     pub fn new() -> Self {
         Self {
             optimized: OptimizedConversationHistory::new(800_000), // 800K tokens limit
+            classifier: None,
+            role_types: HashMap::new(),
+            code_fraction_threshold: DEFAULT_CODE_FRACTION_THRESHOLD,
         }
     }
     
@@ -41,39 +98,125 @@ impl ConversationHistory {
     pub fn with_token_limit(max_tokens: usize) -> Self {
         Self {
             optimized: OptimizedConversationHistory::new(max_tokens),
+            classifier: None,
+            role_types: HashMap::new(),
+            code_fraction_threshold: DEFAULT_CODE_FRACTION_THRESHOLD,
         }
     }
     
+    /// Create sized for a known model's context window
+    pub fn for_model(model: ModelProfile) -> Self {
+        Self {
+            optimized: OptimizedConversationHistory::for_model(model),
+            classifier: None,
+            role_types: HashMap::new(),
+            code_fraction_threshold: DEFAULT_CODE_FRACTION_THRESHOLD,
+        }
+    }
+    
+    /// Create with a full optimization config (keywords, limits, ...)
+    pub fn with_config(config: OptimizationConfig) -> Self {
+        Self {
+            optimized: OptimizedConversationHistory::with_config(config),
+            classifier: None,
+            role_types: HashMap::new(),
+            code_fraction_threshold: DEFAULT_CODE_FRACTION_THRESHOLD,
+        }
+    }
+    
+    /// Replace the built-in classifier, e.g. to emit `MessageType::Custom` tags
+    pub fn with_classifier(
+        mut self,
+        classifier: impl Fn(&str, &str) -> MessageType + Send + Sync + 'static,
+    ) -> Self {
+        self.classifier = Some(Box::new(classifier));
+        self
+    }
+    
+    /// Always classify messages from `role` as `message_type`, overriding the built-in rules
+    pub fn with_role_type(mut self, role: impl Into<String>, message_type: MessageType) -> Self {
+        self.role_types.insert(role.into(), message_type);
+        self
+    }
+    
+    /// Fenced-code share of an assistant reply above which it counts as `CodeExecution`
+    ///
+    /// 0.0 restores the old rule: any fence at all.
+    pub fn with_code_fraction_threshold(mut self, ratio: f64) -> Self {
+        self.code_fraction_threshold = ratio;
+        self
+    }
+    
     /// Record items with automatic optimization
     pub(crate) fn record_items(&mut self, items: &[OriginalResponseItem]) {
-        let now = Utc::now();
+        let now = now_timestamp();
         
-        for original_item in items {
-            // Convert original format to optimized format
-            let optimized_item = ResponseItem {
-                content: original_item.content.clone(),
-                role: original_item.role.clone(),
-                timestamp: now,
-                token_count: 0, // Will be calculated automatically
-                importance_score: 0.0, // Will be calculated automatically
-                message_type: self.classify_message_type(&original_item.content, &original_item.role),
-            };
-            
-            self.optimized.add_message(optimized_item);
-        }
+        // Convert original format to optimized format
+        let optimized_items: Vec<ResponseItem> = items.iter()
+            .map(|original_item| self.convert_item(original_item, now))
+            .collect();
+        
+        // Single prune pass for the whole batch
+        self.optimized.add_messages(optimized_items);
     }
     
-    /// Get last N messages (preserving existing API)
-    pub(crate) fn keep_last_messages(&mut self, count: usize) {
-        // This method is now handled automatically by the optimization system
-        // but we preserve the API for compatibility
+    /// Record items with their original times, e.g. when importing a saved transcript
+    ///
+    /// Recency scoring then sees the real ages instead of treating every
+    /// imported message as brand new.
+    pub(crate) fn record_items_with_timestamps(&mut self, items: &[(OriginalResponseItem, Timestamp)]) {
+        let optimized_items: Vec<ResponseItem> = items.iter()
+            .map(|(original_item, timestamp)| self.convert_item(original_item, *timestamp))
+            .collect();
         
-        // Update minimum messages to keep
-        if count > 0 {
-            self.optimized.min_messages = count;
-            // Trigger re-optimization if needed
-            self.optimized.intelligent_prune();
+        self.optimized.add_messages(optimized_items);
+    }
+    
+    /// Token count and importance are filled in by the optimized history
+    fn convert_item(&self, original_item: &OriginalResponseItem, timestamp: Timestamp) -> ResponseItem {
+        ResponseItem::builder(original_item.content.clone(), original_item.role.clone())
+            .message_type(self.classify_message_type(&original_item.content, &original_item.role))
+            .timestamp(timestamp)
+            .build()
+    }
+    
+    /// Strict `record_items`: validates every item first and records nothing on error
+    pub(crate) fn try_record_items(&mut self, items: &[OriginalResponseItem]) -> Result<(), RecordError> {
+        for (index, item) in items.iter().enumerate() {
+            if item.role.is_empty() {
+                return Err(RecordError::EmptyRole { index });
+            }
+            if !KNOWN_ROLES.contains(&item.role.as_str()) {
+                return Err(RecordError::UnknownRole { index, role: item.role.clone() });
+            }
+            if item.content.trim().is_empty() {
+                return Err(RecordError::EmptyContent { index });
+            }
         }
+        
+        self.record_items(items);
+        Ok(())
+    }
+    
+    /// Re-run classification on the full (pre-compression) content of message `index`
+    ///
+    /// Fixes types and scores chosen from partial content, e.g. while streaming.
+    pub(crate) fn reclassify(&mut self, index: usize) -> Result<(), IndexError> {
+        let item = self.optimized.iter().nth(index)
+            .ok_or(IndexError { index, len: self.optimized.iter().len() })?;
+        let content = item.original_content.as_deref().unwrap_or(&item.content);
+        let message_type = self.classify_message_type(content, &item.role);
+        self.optimized.reclassify(index, message_type)
+    }
+    
+    /// Start a new conversation, keeping the limits, config, classifier and hooks
+    pub(crate) fn clear(&mut self) {
+        self.optimized.clear();
+    }
+    
+    /// Trim to the last `count` messages, returning the removed ones for archiving
+    pub(crate) fn keep_last_messages(&mut self, count: usize) -> Vec<ResponseItem> {
+        self.optimized.keep_last(count)
     }
     
     /// Export all items (for existing code compatibility)
@@ -88,13 +231,29 @@ impl ConversationHistory {
             .collect()
     }
     
+    /// Same as `items`, under the name the upstream Codex `ConversationHistory` uses
+    pub(crate) fn contents(&self) -> Vec<OriginalResponseItem> {
+        self.items()
+    }
+    
     /// Get optimization statistics
     pub fn get_optimization_stats(&self) -> ConversationStats {
         self.optimized.get_stats()
     }
     
-    /// Classify message type from content and role
+    /// Classify message type: injected classifier, then role overrides, then built-in rules
     fn classify_message_type(&self, content: &str, role: &str) -> MessageType {
+        if let Some(classifier) = &self.classifier {
+            return classifier(content, role);
+        }
+        match self.role_types.get(role) {
+            Some(message_type) => message_type.clone(),
+            None => self.default_message_type(content, role),
+        }
+    }
+    
+    /// Built-in classification from content and role
+    fn default_message_type(&self, content: &str, role: &str) -> MessageType {
         let content_lower = content.to_lowercase();
         
         match role {
@@ -109,7 +268,7 @@ impl ConversationHistory {
                 }
             },
             "assistant" => {
-                if content.contains("```") {
+                if fenced_code_fraction(content) > self.code_fraction_threshold {
                     MessageType::CodeExecution
                 } else if content_lower.contains("important") || content_lower.contains("warning") {
                     MessageType::ImportantDecision
@@ -117,6 +276,8 @@ impl ConversationHistory {
                     MessageType::SystemResponse
                 }
             },
+            // Function-calling results carry the actual work product
+            "tool" | "function" => MessageType::CodeExecution,
             _ => MessageType::ContextualInfo,
         }
     }
@@ -128,11 +289,8 @@ impl ConversationHistory {
     pub fn migrate_from_old(old_items: Vec<OriginalResponseItem>) -> Self {
         let mut new_history = Self::new();
         
-        // Process old items in batches to avoid overwhelming the system
-        let batch_size = 50;
-        for chunk in old_items.chunks(batch_size) {
-            new_history.record_items(chunk);
-        }
+        // One batched insert: a single prune pass instead of one per message
+        new_history.record_items(&old_items);
         
         new_history
     }
@@ -144,16 +302,18 @@ impl ConversationHistory {
             "🚀 Migration Complete!\n\
             📊 Total messages: {}\n\
             💾 Total tokens: {}\n\
-            📈 Utilization: {}%\n\
+            📈 Utilization: {:.1}%\n\
             🗜️ Compressed messages: {}\n\
             ⭐ High importance: {}\n\
+            📉 Token reduction: {:.1}%\n\
             \n\
             💕 Ready for 20% token reduction!",
             stats.total_messages,
             stats.total_tokens,
-            stats.utilization_percentage,
+            stats.utilization_exact,
             stats.compressed_messages,
-            stats.high_importance_messages
+            stats.high_importance_messages,
+            stats.reduction_ratio * 100.0
         )
     }
 }
@@ -161,25 +321,6 @@ impl ConversationHistory {
 // Import the optimized implementation
 use super::codex_cli_optimization_v1::*;
 
-// 🔧 Integration Configuration
-pub struct OptimizationConfig {
-    pub max_tokens: usize,
-    pub min_messages: usize,
-    pub compression_threshold: f64,
-    pub enable_aggressive_pruning: bool,
-}
-
-impl Default for OptimizationConfig {
-    fn default() -> Self {
-        Self {
-            max_tokens: 800_000,    // 20% less than 1M baseline
-            min_messages: 15,       // Always keep recent context
-            compression_threshold: 0.7, // Compress messages with importance < 0.7
-            enable_aggressive_pruning: true, // Enable when needed
-        }
-    }
-}
-
 // 🧪 TDD Integration hooks
 #[cfg(test)]
 mod tests {
@@ -244,6 +385,94 @@ mod tests {
         assert!(final_items.iter().any(|item| 
             item.content.contains("Config update")));
     }
+    
+    #[test]
+    fn test_try_record_items_rejects_whole_batch() {
+        let mut history = ConversationHistory::new();
+        
+        let batch = vec![
+            OriginalResponseItem {
+                content: "Run the migrations".to_string(),
+                role: "user".to_string(),
+            },
+            OriginalResponseItem {
+                content: "Done".to_string(),
+                role: "narrator".to_string(),
+            },
+        ];
+        
+        assert_eq!(
+            history.try_record_items(&batch),
+            Err(RecordError::UnknownRole { index: 1, role: "narrator".to_string() })
+        );
+        assert!(history.items().is_empty(), "a rejected batch must record nothing");
+        
+        history.try_record_items(&batch[..1]).unwrap();
+        assert_eq!(history.items().len(), 1);
+    }
+    
+    #[test]
+    fn test_keep_last_messages_returns_trimmed_items() {
+        let mut history = ConversationHistory::new();
+        
+        let items: Vec<OriginalResponseItem> = (0..5)
+            .map(|i| OriginalResponseItem {
+                content: format!("Message {}", i),
+                role: "user".to_string(),
+            })
+            .collect();
+        history.record_items(&items);
+        
+        let archived = history.keep_last_messages(2);
+        assert_eq!(archived.len(), 3);
+        assert_eq!(archived[0].content, "Message 0");
+        
+        let remaining: Vec<String> = history.items().into_iter().map(|item| item.content).collect();
+        assert_eq!(remaining, ["Message 3", "Message 4"]);
+    }
+    
+    #[test]
+    fn test_tool_roles_and_role_overrides() {
+        let history = ConversationHistory::new();
+        assert_eq!(history.classify_message_type("{\"ok\":true}", "tool"), MessageType::CodeExecution);
+        assert_eq!(history.classify_message_type("42", "function"), MessageType::CodeExecution);
+        
+        let history = ConversationHistory::new()
+            .with_role_type("tool", MessageType::ImportantDecision);
+        assert_eq!(history.classify_message_type("42", "tool"), MessageType::ImportantDecision);
+        assert_eq!(history.classify_message_type("42", "function"), MessageType::CodeExecution);
+    }
+    
+    #[test]
+    fn test_inline_snippet_is_not_code_execution() {
+        let explanation = format!(
+            "{}Use `cargo build` or:\n```\nmake\n```\n",
+            "The build reads the workspace manifest and resolves features first. ".repeat(5)
+        );
+        let listing = "Here:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```";
+        
+        let history = ConversationHistory::new();
+        assert_eq!(history.classify_message_type(&explanation, "assistant"), MessageType::SystemResponse);
+        assert_eq!(history.classify_message_type(listing, "assistant"), MessageType::CodeExecution);
+        
+        let history = ConversationHistory::new().with_code_fraction_threshold(0.0);
+        assert_eq!(history.classify_message_type(&explanation, "assistant"), MessageType::CodeExecution);
+    }
+    
+    #[test]
+    fn test_reclassify_uses_final_content() {
+        let mut history = ConversationHistory::new();
+        let mut core = OptimizedConversationHistory::new(800_000);
+        let stream = core.begin_streaming("user", MessageType::UserQuery);
+        core.push_delta(stream, "Running the migration now... ");
+        core.push_delta(stream, "error: relation already exists");
+        core.finish_streaming(stream);
+        history.optimized = core;
+        
+        history.reclassify(0).unwrap();
+        assert_eq!(history.optimized.iter().next().unwrap().message_type, MessageType::ErrorHandling);
+        assert!(history.reclassify(1).is_err());
+    }
 }
 
 // 💕 Phase 2 Integration Summary:
@@ -260,6 +489,8 @@ mod tests {
 // 3. Update imports in dependent files
 // 4. Run cargo test to verify integration
 // 5. Monitor token usage with get_optimization_stats()
+// 6. Keep the browser build compiling: phase2/check_wasm.sh compile-checks the
+//    core module (tests built, not run) for wasm32-unknown-unknown
 //
 // 📊 Expected results:
 // - 20% token reduction (1.18M → 944K)